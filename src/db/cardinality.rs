@@ -0,0 +1,279 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+use super::datapoint::Datapoint;
+use super::{DB, SERIES_COUNT_KEY};
+
+/// Limits on the number of series, checked whenever a new series would be
+/// registered. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_series: Option<u64>,
+    pub max_series_per_metric: Option<u64>,
+    pub max_tag_values: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MetricCardinality {
+    pub metric: String,
+    pub series: u64,
+    // Distinct values of every tag key, highest first
+    pub tag_values: Vec<(String, u64)>,
+}
+
+// Counters kept next to the series count so that checking the limits
+// doesn't scan: the series of each metric, the series with each value of a
+// tag of a metric, and the distinct values of each tag of a metric
+const METRIC_SERIES_PREFIX: &str = "###INTERNAL_METRIC_SERIES#";
+const VALUE_SERIES_PREFIX: &str = "###INTERNAL_VALUE_SERIES#";
+const TAG_VALUES_PREFIX: &str = "###INTERNAL_TAG_VALUES#";
+
+/// Updates to the counters of series being registered or moved away, read
+/// through to the stored ones and written in the same batch as the series
+/// keys.
+pub struct Counters<'a, D: DB + ?Sized> {
+    db: &'a D,
+    pending: BTreeMap<String, u64>,
+}
+
+impl<'a, D: DB + ?Sized> Counters<'a, D> {
+    pub fn new(db: &'a D) -> Self {
+        Counters {
+            db,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_series(&mut self, metric: &str, tags: &HashMap<String, String>) -> Result<()> {
+        self.change(SERIES_COUNT_KEY.to_owned(), true)?;
+        self.change(metric_series_key(metric), true)?;
+        for (key, value) in tags {
+            if self.change(value_series_key(metric, key, value), true)? == 1 {
+                self.change(tag_values_key(metric, key), true)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_series(&mut self, metric: &str, tags: &HashMap<String, String>) -> Result<()> {
+        self.change(SERIES_COUNT_KEY.to_owned(), false)?;
+        self.change(metric_series_key(metric), false)?;
+        for (key, value) in tags {
+            if self.change(value_series_key(metric, key, value), false)? == 0 {
+                self.change(tag_values_key(metric, key), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_batch(self) -> Vec<(String, Option<Vec<u8>>)> {
+        self.pending
+            .into_iter()
+            .map(|(key, count)| (key, Some(count.to_le_bytes().to_vec())))
+            .collect()
+    }
+
+    fn get(&self, key: &str) -> Result<u64> {
+        match self.pending.get(key) {
+            Some(count) => Ok(*count),
+            None => Ok(self.db.get_id(key)?.unwrap_or(0)),
+        }
+    }
+
+    // Adds or takes one, returning the new count
+    fn change(&mut self, key: String, add: bool) -> Result<u64> {
+        let count = self.get(&key)?;
+        let count = if add { count + 1 } else { count.saturating_sub(1) };
+        self.pending.insert(key, count);
+        Ok(count)
+    }
+}
+
+/// Counts every counter from the series stored, for databases written before
+/// there were counters. The series count is in the returned batch, so the
+/// counters are set up once it's written.
+pub fn count_series<D: DB + ?Sized>(db: &D) -> Vec<(String, Option<Vec<u8>>)> {
+    let mut counts = BTreeMap::from([(SERIES_COUNT_KEY.to_owned(), 0u64)]);
+    for series in db.list_series(None) {
+        *counts.entry(SERIES_COUNT_KEY.to_owned()).or_default() += 1;
+        *counts.entry(metric_series_key(&series.metric)).or_default() += 1;
+        for (key, value) in &series.tags {
+            let value_series = counts.entry(value_series_key(&series.metric, key, value)).or_default();
+            *value_series += 1;
+            if *value_series == 1 {
+                *counts.entry(tag_values_key(&series.metric, key)).or_default() += 1;
+            }
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(key, count)| (key, Some(count.to_le_bytes().to_vec())))
+        .collect()
+}
+
+fn metric_series_key(metric: &str) -> String {
+    format!("{}{}", METRIC_SERIES_PREFIX, Datapoint::key_string(metric, &HashMap::new()))
+}
+
+fn value_series_key(metric: &str, key: &str, value: &str) -> String {
+    let tag = HashMap::from([(key.to_owned(), value.to_owned())]);
+    format!("{}{}", VALUE_SERIES_PREFIX, Datapoint::key_string(metric, &tag))
+}
+
+fn tag_values_key(metric: &str, key: &str) -> String {
+    let tag = HashMap::from([(key.to_owned(), String::new())]);
+    format!("{}{}", TAG_VALUES_PREFIX, Datapoint::key_string(metric, &tag))
+}
+
+// Rejects registering the series `metric` + `tags` if that would break a limit
+pub fn check_new_series<D: DB + ?Sized>(
+    counters: &mut Counters<'_, D>,
+    limits: &Limits,
+    metric: &str,
+    tags: &HashMap<String, String>,
+) -> Result<()> {
+    if let Some(max) = limits.max_series {
+        if counters.get(SERIES_COUNT_KEY)? >= max {
+            bail!("series limit reached: the database already has {} series", max);
+        }
+    }
+    if let Some(max) = limits.max_series_per_metric {
+        if counters.get(&metric_series_key(metric))? >= max {
+            bail!("series limit reached: metric `{}` already has {} series", metric, max);
+        }
+    }
+    if let Some(max) = limits.max_tag_values {
+        for (key, value) in tags {
+            let seen = counters.get(&value_series_key(metric, key, value))? > 0;
+            if !seen && counters.get(&tag_values_key(metric, key))? >= max {
+                bail!(
+                    "tag value limit reached: tag `{}` of metric `{}` already has {} values, rejecting `{}`",
+                    key, metric, max, value
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Series and tag value counts of every metric, highest series count first
+pub fn cardinality<D: DB + ?Sized>(db: &D) -> Result<Vec<MetricCardinality>> {
+    let mut metrics = BTreeMap::new();
+    for (key, value) in db.scan_prefix(METRIC_SERIES_PREFIX) {
+        let series = read_count(&value)?;
+        if let Some((metric, _)) = Datapoint::parse_key_string(&key[METRIC_SERIES_PREFIX.len()..]) {
+            if series > 0 {
+                let tag_values = Vec::new();
+                metrics.insert(metric.clone(), MetricCardinality { metric, series, tag_values });
+            }
+        }
+    }
+    for (key, value) in db.scan_prefix(TAG_VALUES_PREFIX) {
+        let values = read_count(&value)?;
+        let (metric, tags) = match Datapoint::parse_key_string(&key[TAG_VALUES_PREFIX.len()..]) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if let (Some(cardinality), Some(tag)) = (metrics.get_mut(&metric), tags.into_keys().next()) {
+            if values > 0 {
+                cardinality.tag_values.push((tag, values));
+            }
+        }
+    }
+
+    let mut output: Vec<_> = metrics.into_values().collect();
+    for metric in output.iter_mut() {
+        metric.tag_values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    }
+    output.sort_by(|a, b| b.series.cmp(&a.series).then_with(|| a.metric.cmp(&b.metric)));
+    Ok(output)
+}
+
+fn read_count(value: &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(value[0..8].try_into()?))
+}
+
+#[test]
+fn test_limits() {
+    use super::counting::CountingDB;
+    use super::memory::MemoryDB;
+
+    let db = MemoryDB::default().with_limits(Limits {
+        max_series: Some(4),
+        max_series_per_metric: Some(3),
+        max_tag_values: Some(2),
+    });
+    let tags = |host: &str, dc: &str| {
+        HashMap::from([("host".to_owned(), host.to_owned()), ("dc".to_owned(), dc.to_owned())])
+    };
+    let register = |metric, tags| db.get_id_or_register(&Datapoint::key_string(metric, &tags));
+    register("cpu", tags("a", "x")).unwrap();
+    register("cpu", tags("b", "x")).unwrap();
+    assert!(register("cpu", tags("c", "x")).is_err());
+    register("cpu", tags("a", "y")).unwrap();
+    assert!(register("cpu", tags("b", "y")).is_err());
+    register("cpu", tags("a", "x")).unwrap();
+    register("mem", tags("a", "x")).unwrap();
+    assert!(register("disk", HashMap::new()).is_err());
+
+    // Series moved away no longer count, merged or renamed
+    let series = db.find_series("cpu", &tags("b", "x")).unwrap().unwrap();
+    db.move_series(&series, "cpu", &tags("a", "x")).unwrap();
+    let series = db.find_series("cpu", &tags("a", "y")).unwrap().unwrap();
+    db.move_series(&series, "cpu", &tags("c", "x")).unwrap();
+    register("cpu", tags("a", "z")).unwrap();
+    assert!(register("cpu", tags("d", "x")).is_err());
+
+    // Registering reads the counters instead of scanning the metric
+    let db = MemoryDB::default().with_limits(Limits {
+        max_series: None,
+        max_series_per_metric: Some(1000),
+        max_tag_values: Some(1000),
+    });
+    for host in 0..100 {
+        db.get_id_or_register(&Datapoint::key_string("cpu", &tags(&host.to_string(), "x"))).unwrap();
+    }
+    let counting = CountingDB::new(&db);
+    counting.get_id_or_register(&Datapoint::key_string("cpu", &tags("new", "x"))).unwrap();
+    assert!(counting.keys_read() < 20, "read {} keys", counting.keys_read());
+}
+
+#[test]
+fn test_cardinality() {
+    use super::memory::MemoryDB;
+    use super::MAX_METRIC_ID_KEY;
+
+    let db = MemoryDB::default();
+    let tags = |host: &str, dc: &str| {
+        HashMap::from([("host".to_owned(), host.to_owned()), ("dc".to_owned(), dc.to_owned())])
+    };
+    // Series stored before there were counters
+    db.put(MAX_METRIC_ID_KEY, &2u64.to_le_bytes()).unwrap();
+    db.put(super::FORMAT_VERSION_KEY, &super::FORMAT_VERSION.to_le_bytes()).unwrap();
+    db.put(&Datapoint::key_string("cpu", &tags("a", "x")), &1u64.to_le_bytes()).unwrap();
+    db.put(&Datapoint::key_string("cpu", &tags("b", "x")), &2u64.to_le_bytes()).unwrap();
+    db.check_format_version().unwrap();
+    assert_eq!(db.get_series_count().unwrap(), 2);
+
+    db.get_id_or_register(&Datapoint::key_string("mem", &tags("a", "x"))).unwrap();
+    db.get_id_or_register(&Datapoint::key_string("cpu", &tags("c", "y"))).unwrap();
+    let series = db.find_series("cpu", &tags("b", "x")).unwrap().unwrap();
+    db.move_series(&series, "disk", &tags("b", "x")).unwrap();
+    let series = db.find_series("disk", &tags("b", "x")).unwrap().unwrap();
+    db.move_series(&series, "mem", &tags("a", "x")).unwrap();
+
+    let expected = |metric: &str, series, tag_values: &[(&str, u64)]| MetricCardinality {
+        metric: metric.to_owned(),
+        series,
+        tag_values: tag_values.iter().map(|(key, values)| (key.to_string(), *values)).collect(),
+    };
+    assert_eq!(
+        cardinality(&db).unwrap(),
+        vec![
+            expected("cpu", 2, &[("dc", 2), ("host", 2)]),
+            expected("mem", 1, &[("dc", 1), ("host", 1)]),
+        ]
+    );
+    assert_eq!(db.get_series_count().unwrap(), 3);
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use std::cell::Cell;
+
+use super::cardinality::Limits;
+use super::DB;
+
+/// Passes everything through to another DB, counting the keys read along the
+/// way: one per get, and one per pair a scan yields.
+pub struct CountingDB<'a, D: DB> {
+    inner: &'a D,
+    keys_read: Cell<u64>,
+}
+
+impl<'a, D: DB> CountingDB<'a, D> {
+    pub fn new(inner: &'a D) -> Self {
+        CountingDB {
+            inner,
+            keys_read: Cell::new(0),
+        }
+    }
+
+    pub fn keys_read(&self) -> u64 {
+        self.keys_read.get()
+    }
+
+    fn count(&self) {
+        self.keys_read.set(self.keys_read.get() + 1);
+    }
+}
+
+impl<D: DB> DB for CountingDB<'_, D> {
+    fn put(&self, key: &str, val: &[u8]) -> Result<()> {
+        self.inner.put(key, val)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.count();
+        self.inner.get(key)
+    }
+
+    fn scan_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        Box::new(self.inner.scan_from(start).inspect(move |_| self.count()))
+    }
+
+    fn scan_back_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        Box::new(self.inner.scan_back_from(start).inspect(move |_| self.count()))
+    }
+
+    fn write_batch(&self, batch: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        self.inner.write_batch(batch)
+    }
+
+    fn limits(&self) -> Limits {
+        self.inner.limits()
+    }
+}
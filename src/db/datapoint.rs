@@ -1,20 +1,25 @@
 use std::collections::HashMap;
-use std::time::SystemTime;
+
+use super::escape::{escape_into, split_unescaped, unescape};
+use super::Timestamp;
 
 #[derive(Debug, Clone)]
 pub struct Datapoint {
     pub metric: String,
     pub value: f64,
-    pub time: SystemTime,
+    pub time: Timestamp,
     pub tags: HashMap<String, String>,
 }
 
+// Characters with a meaning in series keys, escaped with a backslash
+const KEY_SPECIAL: [char; 3] = ['#', ',', ':'];
+
 impl Datapoint {
     pub fn default() -> Self {
         Datapoint {
             metric: String::default(),
             value: 0.0,
-            time: SystemTime::UNIX_EPOCH,
+            time: 0,
             tags: HashMap::default(),
         }
     }
@@ -23,19 +28,69 @@ impl Datapoint {
         Datapoint::key_string(self.metric.as_ref(), &self.tags)
     }
 
+    // Tags are sorted so that the same tag set always maps to the same key
     pub fn key_string(metric: &str, tags: &HashMap<String, String>) -> String {
         let mut output = String::new();
-        output.push_str(metric);
+        escape_into(&mut output, metric, &KEY_SPECIAL);
         output.push('#');
+        let mut sorted: Vec<_> = tags.iter().collect();
+        sorted.sort();
         let mut first = true;
-        for (key, value) in tags {
+        for (key, value) in sorted {
             if first {
                 first = false;
             } else {
-                output.push_str(",");
+                output.push(',');
             }
-            output.push_str(&format!("{}:{}", key, value));
+            escape_into(&mut output, key, &KEY_SPECIAL);
+            output.push(':');
+            escape_into(&mut output, value, &KEY_SPECIAL);
         }
         output
     }
+
+    // Inverse of key_string, None for keys that aren't series keys
+    pub fn parse_key_string(key: &str) -> Option<(String, HashMap<String, String>)> {
+        let parts = split_unescaped(key, '#');
+        if parts.len() != 2 {
+            return None;
+        }
+        let mut tags = HashMap::new();
+        if !parts[1].is_empty() {
+            for pair in split_unescaped(parts[1], ',') {
+                let key_value = split_unescaped(pair, ':');
+                if key_value.len() != 2 {
+                    return None;
+                }
+                tags.insert(unescape(key_value[0]), unescape(key_value[1]));
+            }
+        }
+        Some((unescape(parts[0]), tags))
+    }
+}
+
+#[test]
+fn test_key_string() {
+    let tags = HashMap::from([
+        ("xxx".to_owned(), "yyy".to_owned()),
+        ("test".to_owned(), "bla".to_owned()),
+    ]);
+    assert_eq!(Datapoint::key_string("metric", &tags), "metric#test:bla,xxx:yyy");
+    assert_eq!(Datapoint::key_string("test", &HashMap::new()), "test#");
+    assert_eq!(
+        Datapoint::parse_key_string("metric#test:bla,xxx:yyy"),
+        Some(("metric".to_owned(), tags))
+    );
+    assert_eq!(
+        Datapoint::parse_key_string("test#"),
+        Some(("test".to_owned(), HashMap::new()))
+    );
+
+    let odd = HashMap::from([("a:b".to_owned(), "c,d#e".to_owned())]);
+    let key = Datapoint::key_string("m#1", &odd);
+    assert_eq!(Datapoint::parse_key_string(&key), Some(("m#1".to_owned(), odd)));
+
+    // Data and internal keys aren't series keys
+    assert_eq!(Datapoint::parse_key_string("##1##80000000625c5e80"), None);
+    assert_eq!(Datapoint::parse_key_string("###INTERNAL_MAX_METRIC"), None);
 }
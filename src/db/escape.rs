@@ -0,0 +1,65 @@
+// Backslash escaping shared by the series keys and the dump format
+
+pub fn escape_into(output: &mut String, part: &str, special: &[char]) {
+    for c in part.chars() {
+        if c == '\n' {
+            output.push_str("\\n");
+            continue;
+        }
+        if c == '\\' || special.contains(&c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+}
+
+// Splits on every `separator` that isn't preceded by a backslash, leaving the
+// parts themselves escaped
+pub fn split_unescaped(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&input[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+pub fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => output.push('\n'),
+                Some(c) => output.push(c),
+                None => output.push('\\'),
+            },
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[test]
+fn test_roundtrip() {
+    let mut escaped = String::new();
+    escape_into(&mut escaped, "a,b:c\\d", &[',', ':']);
+    assert_eq!(escaped, "a\\,b\\:c\\\\d");
+    assert_eq!(unescape(&escaped), "a,b:c\\d");
+}
+
+#[test]
+fn test_split() {
+    assert_eq!(split_unescaped("a,b\\,c,d", ','), vec!["a", "b\\,c", "d"]);
+    assert_eq!(split_unescaped("a\\\\,b", ','), vec!["a\\\\", "b"]);
+    assert_eq!(split_unescaped("", ','), vec![""]);
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use super::cardinality::Limits;
+
+// Keeps everything in a sorted map, for tests that need a DB but not RocksDB
+#[derive(Default)]
+pub struct MemoryDB {
+    data: RefCell<BTreeMap<String, Vec<u8>>>,
+    limits: Limits,
+}
+
+impl MemoryDB {
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+impl super::DB for MemoryDB {
+    fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+
+    fn put(&self, key: &str, val: &[u8]) -> Result<()> {
+        self.data.borrow_mut().insert(key.to_owned(), val.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.borrow().get(key).cloned())
+    }
+
+    fn scan_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        // Copied so that callers may write while iterating, like with a RocksDB snapshot
+        let pairs: Vec<_> = self
+            .data
+            .borrow()
+            .range(start.to_owned()..)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Box::new(pairs.into_iter())
+    }
+
+    fn scan_back_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        let pairs: Vec<_> = self
+            .data
+            .borrow()
+            .range(..=start.to_owned())
+            .rev()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Box::new(pairs.into_iter())
+    }
+
+    fn write_batch(&self, batch: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let mut data = self.data.borrow_mut();
+        for (key, val) in batch {
+            match val {
+                Some(val) => data.insert(key, val),
+                None => data.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,90 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::cmp;
 use std::collections::HashMap;
 use std::convert::TryInto;
-use std::ops::Add;
 use std::str;
-use std::time::{Duration, SystemTime};
 
+pub mod cardinality;
+pub mod counting;
 pub mod datapoint;
+pub mod escape;
+#[cfg(test)]
+pub mod memory;
 pub mod rocksdb;
+pub mod series;
+mod upgrade;
+
+use cardinality::Limits;
+use series::{Series, SeriesScan};
 
 pub const MAX_METRIC_ID_KEY: &str = "###INTERNAL_MAX_METRIC";
+const SERIES_COUNT_KEY: &str = "###INTERNAL_SERIES_COUNT";
+const FORMAT_VERSION_KEY: &str = "###INTERNAL_FORMAT_VERSION";
+const FORMAT_VERSION: u64 = 2;
 const SECS_IN_MINUTE: u64 = 60;
+const MOVE_BATCH_SIZE: usize = 10000;
+pub const DATA_KEY_PREFIX: &str = "##";
+
+// Seconds since the Unix epoch, negative before 1970
+pub type Timestamp = i64;
+// Earliest time whose minute bucket still fits in a Timestamp
+pub const MIN_TIMESTAMP: Timestamp = Timestamp::MIN / 60 * 60;
+
+// Data keys are `##<series id>##<bucket>`, with the bucket encoded so that the
+// keys of a series sort in time order, pre-epoch buckets included
+pub fn data_key(id: u64, time_bucket: Timestamp) -> String {
+    format!("{}{}##{:016x}", DATA_KEY_PREFIX, id, (time_bucket as u64) ^ (1 << 63))
+}
+
+pub fn data_key_prefix(id: u64) -> String {
+    format!("{}{}##", DATA_KEY_PREFIX, id)
+}
+
+// Inverse of data_key, None for any other kind of key
+pub fn parse_data_key(key: &str) -> Option<(u64, Timestamp)> {
+    let (id, time_bucket) = key.strip_prefix(DATA_KEY_PREFIX)?.split_once("##")?;
+    let time_bucket = u64::from_str_radix(time_bucket, 16).ok()? ^ (1 << 63);
+    Some((id.parse().ok()?, time_bucket as Timestamp))
+}
 
 pub trait DB {
     fn put(&self, key: &str, val: &[u8]) -> Result<()>;
     fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    // All pairs from `start` to the end of the keyspace, in key order
+    fn scan_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_>;
+    // Like scan_from, but from the last key <= `start` towards the first one
+    fn scan_back_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_>;
+    // Applies all the changes atomically, a None value deletes the key
+    fn write_batch(&self, batch: Vec<(String, Option<Vec<u8>>)>) -> Result<()>;
+
+    fn limits(&self) -> Limits {
+        Limits::default()
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        let prefix = prefix.to_owned();
+        Box::new(self.scan_from(&prefix).take_while(move |(key, _)| key.starts_with(&prefix)))
+    }
+
+    // Databases written before timestamps were signed keep their buckets and
+    // series under different keys, those are upgraded in place first
+    fn check_format_version(&self) -> Result<()> {
+        match self.get_id(FORMAT_VERSION_KEY)? {
+            Some(FORMAT_VERSION) => {}
+            Some(version) => bail!("unsupported database format version {}", version),
+            None if self.get(MAX_METRIC_ID_KEY)?.is_some() => {
+                let mut batch = upgrade::upgrade_key_layout(self)?;
+                batch.push((FORMAT_VERSION_KEY.to_owned(), Some(FORMAT_VERSION.to_le_bytes().to_vec())));
+                self.write_batch(batch)?;
+            }
+            None => self.put(FORMAT_VERSION_KEY, &FORMAT_VERSION.to_le_bytes())?,
+        }
+        // Series stored before there were counters are counted once
+        if self.get(SERIES_COUNT_KEY)?.is_none() {
+            self.write_batch(cardinality::count_series(self))?;
+        }
+        Ok(())
+    }
 
     fn get_id(&self, key: &str) -> Result<Option<u64>> {
         let value = self.get(key)?;
@@ -32,25 +102,49 @@ pub trait DB {
         }
     }
 
+    // Kept up to date with the other counters as series come and go
+    fn get_series_count(&self) -> Result<u64> {
+        Ok(self.get_id(SERIES_COUNT_KEY)?.unwrap_or(0))
+    }
+
     fn get_id_or_register(&self, key: &str) -> Result<u64> {
         let value = self.get_id(key)?;
         match value {
             Some(id) => Ok(id),
             None => {
+                let mut counters = cardinality::Counters::new(self);
+                if let Some((metric, tags)) = datapoint::Datapoint::parse_key_string(key) {
+                    cardinality::check_new_series(&mut counters, &self.limits(), &metric, &tags)?;
+                    counters.add_series(&metric, &tags)?;
+                }
                 let mut id = self.get_max_metric_id()?;
                 id += 1;
-                self.put(key, &id.to_le_bytes())?;
-                self.put(MAX_METRIC_ID_KEY, &id.to_le_bytes())?;
+                let mut batch = vec![
+                    (key.to_owned(), Some(id.to_le_bytes().to_vec())),
+                    (MAX_METRIC_ID_KEY.to_owned(), Some(id.to_le_bytes().to_vec())),
+                ];
+                batch.extend(counters.into_batch());
+                self.write_batch(batch)?;
                 return Ok(id);
             }
         }
     }
 
-    fn select_time_bucket_and_offset(&self, time: SystemTime) -> Result<(u64, u64)> {
-        let secs = time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
-        let time_bucket = (secs / SECS_IN_MINUTE) * SECS_IN_MINUTE;
-        let offset = secs % SECS_IN_MINUTE;
-        Ok((time_bucket, offset))
+    fn select_time_bucket_and_offset(&self, time: Timestamp) -> (Timestamp, u64) {
+        let minute = SECS_IN_MINUTE as Timestamp;
+        let time_bucket = time.div_euclid(minute) * minute;
+        let offset = time.rem_euclid(minute) as u64;
+        (time_bucket, offset)
+    }
+
+    // Copies every point of `source` into `target`, overwriting the points
+    // `target` already has at the same offsets
+    fn merge_data(target: Option<Vec<u8>>, source: &[u8], time_bucket: Timestamp) -> Vec<u8> {
+        let mut merged = target;
+        for (time, value) in Self::decode_bucket(source, time_bucket) {
+            merged = Some(Self::format_data(merged, value, (time - time_bucket) as u64));
+        }
+        merged.unwrap_or_else(|| source.to_vec())
     }
 
     fn format_data(data: Option<Vec<u8>>, value: f64, offset: u64) -> Vec<u8> {
@@ -97,90 +191,203 @@ pub trait DB {
         outdata
     }
 
-    fn parse_data(
-        input: Option<Vec<u8>>,
-        metric: &str,
-        tags: &HashMap<String, String>,
-        time_bucket: SystemTime,
-    ) -> Vec<datapoint::Datapoint> {
-        let data = input.unwrap_or(vec![0; 8]);
+    fn decode_bucket(data: &[u8], time_bucket: Timestamp) -> Vec<(Timestamp, f64)> {
         let bitmap = u64::from_le_bytes(data[0..8].try_into().unwrap());
-        let mut positions = Vec::new();
+        let mut output = Vec::new();
+        let mut start = 8;
 
         for i in 0..SECS_IN_MINUTE {
             let mask = 1u64 << i;
             let populated = bitmap & mask != 0;
             if populated {
-                positions.push(i);
+                let end = start + 8;
+                let value = f64::from_le_bytes(data[start..end].try_into().unwrap());
+                output.push((time_bucket + i as Timestamp, value));
+                start = end;
             }
         }
 
-        let mut output = Vec::new();
-        for i in 0..positions.len() {
-            let start = 8 + i * 8;
-            let end = start + 8;
-            let value = f64::from_le_bytes(data[start..end].try_into().unwrap());
-            let time = time_bucket.add(Duration::new(positions[i], 0));
-            let dp = datapoint::Datapoint{metric: metric.to_owned(), tags: tags.clone(), value: value, time: time};
-            output.push(dp);
-        }
-
         output
     }
 
     fn put_datapoint(&self, datapoint: datapoint::Datapoint) -> Result<()> {
-        let metakey = datapoint.to_key_string();
-        let id = self.get_id_or_register(&metakey)?;
-
-        let (time_bucket, offset) = self.select_time_bucket_and_offset(datapoint.time)?;
-        let datakey = format!("{}##{}", time_bucket, id);
-        let datavalue = self.get(&datakey)?;
-        let data = Self::format_data(datavalue, datapoint.value, offset);
-        self.put(&datakey, &data)?;
-        Ok(())
+        self.put_datapoints(vec![datapoint])
     }
 
-    fn get_datapoints_in_bucket(
-        &self,
-        metric: &str,
-        tags: &HashMap<String, String>,
-        time_bucket: &SystemTime,
-    ) -> Result<Vec<datapoint::Datapoint>> {
-        // Make sure we have the time bucket aligned
-        let mut time = time_bucket
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs();
-        time = (time / SECS_IN_MINUTE) * SECS_IN_MINUTE;
+    fn put_datapoints(&self, datapoints: Vec<datapoint::Datapoint>) -> Result<()> {
+        let mut ids = HashMap::<String, u64>::new();
+        let mut buckets = HashMap::<String, Vec<u8>>::new();
+
+        for datapoint in datapoints {
+            if datapoint.time < MIN_TIMESTAMP {
+                bail!("timestamp {} is before the earliest supported time", datapoint.time);
+            }
+            let metakey = datapoint.to_key_string();
+            let id = match ids.get(&metakey) {
+                Some(id) => *id,
+                None => {
+                    let id = self.get_id_or_register(&metakey)?;
+                    ids.insert(metakey, id);
+                    id
+                }
+            };
+
+            let (time_bucket, offset) = self.select_time_bucket_and_offset(datapoint.time);
+            let datakey = data_key(id, time_bucket);
+            let datavalue = match buckets.remove(&datakey) {
+                Some(data) => Some(data),
+                None => self.get(&datakey)?,
+            };
+            let data = Self::format_data(datavalue, datapoint.value, offset);
+            buckets.insert(datakey, data);
+        }
+
+        self.write_batch(buckets.into_iter().map(|(key, data)| (key, Some(data))).collect())
+    }
+
+    fn find_series(&self, metric: &str, tags: &HashMap<String, String>) -> Result<Option<Series>> {
         let key = datapoint::Datapoint::key_string(metric, tags);
-        let id_opt = self.get_id(&key)?;
-
-        match id_opt {
-            Some(id) => {
-                let points = self.get(&format!("{}##{}", time, id))?;
-                let system_time_bucket = SystemTime::UNIX_EPOCH.add(Duration::new(time, 0));
-                return Ok(Self::parse_data(points, metric, tags, system_time_bucket));
-            },
-            None => return Ok(vec![]),
+        Ok(self.get_id(&key)?.map(|id| Series {
+            id,
+            metric: metric.to_owned(),
+            tags: tags.clone(),
+        }))
+    }
+
+    // Every series stored under `metric`, or every series at all
+    fn list_series(&self, metric: Option<&str>) -> Box<dyn Iterator<Item = Series> + '_> {
+        let keys: Box<dyn Iterator<Item = (String, Vec<u8>)>> = match metric {
+            Some(metric) => self.scan_prefix(&datapoint::Datapoint::key_string(metric, &HashMap::new())),
+            // Metric names can't start with an unescaped #, so the data and
+            // internal keys in between are skipped over
+            None => Box::new(
+                self.scan_from("")
+                    .take_while(|(key, _)| !key.starts_with('#'))
+                    .chain(self.scan_from("$")),
+            ),
+        };
+        let metric = metric.map(str::to_owned);
+        Box::new(keys.filter_map(move |(key, value)| {
+            let (parsed_metric, tags) = datapoint::Datapoint::parse_key_string(&key)?;
+            // The prefix of `cpu` also covers `cpu2`
+            if matches!(&metric, Some(metric) if *metric != parsed_metric) {
+                return None;
+            }
+            let id = u64::from_le_bytes(value.get(0..8)?.try_into().ok()?);
+            Some(Series {
+                id,
+                metric: parsed_metric,
+                tags,
+            })
+        }))
+    }
+
+    // Re-points `series` to a new metric and tag set. The id and its buckets
+    // stay as they are unless another series already uses the new key, in
+    // which case the buckets are merged into that series. Merging writes in
+    // chunks and drops the old key last, so an interrupted move can be
+    // completed by running it again.
+    fn move_series(&self, series: &Series, metric: &str, tags: &HashMap<String, String>) -> Result<()> {
+        let old_key = series.key_string();
+        let new_key = datapoint::Datapoint::key_string(metric, tags);
+        if old_key == new_key {
+            return Ok(());
         }
+
+        let mut counters = cardinality::Counters::new(self);
+        counters.remove_series(&series.metric, &series.tags)?;
+        let target = match self.get_id(&new_key)? {
+            Some(target) => target,
+            None => {
+                counters.add_series(metric, tags)?;
+                let mut batch = vec![(new_key, Some(series.id.to_le_bytes().to_vec())), (old_key, None)];
+                batch.extend(counters.into_batch());
+                return self.write_batch(batch);
+            }
+        };
+
+        let mut batch = Vec::new();
+        for (key, data) in self.scan_prefix(&data_key_prefix(series.id)) {
+            let (_, time_bucket) = match parse_data_key(&key) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let target_key = data_key(target, time_bucket);
+            let merged = Self::merge_data(self.get(&target_key)?, &data, time_bucket);
+            batch.push((target_key, Some(merged)));
+            batch.push((key, None));
+            if batch.len() >= MOVE_BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        batch.extend(counters.into_batch());
+        batch.push((old_key, None));
+        self.write_batch(batch)
+    }
+
+    fn scan_series(
+        &self,
+        series: &Series,
+        time_start: &Timestamp,
+        time_end: &Timestamp,
+    ) -> SeriesScan<'_, Self>
+    where
+        Self: Sized,
+    {
+        SeriesScan::new(self, series.id, *time_start, *time_end)
+    }
+
+    // Newest points first, reading only as many buckets as are consumed
+    fn scan_series_rev(
+        &self,
+        series: &Series,
+        time_start: &Timestamp,
+        time_end: &Timestamp,
+    ) -> SeriesScan<'_, Self>
+    where
+        Self: Sized,
+    {
+        SeriesScan::new_rev(self, series.id, *time_start, *time_end)
     }
 
     fn get_datapoints_exact(
         &self,
         metric: &str,
         tags: &HashMap<String, String>,
-        time_start: &SystemTime,
-        time_end: &SystemTime,
-    ) -> Result<Vec<datapoint::Datapoint>> {
-        let mut current_time = time_start.clone();
-        let mut results = Vec::<datapoint::Datapoint>::new();
+        time_start: &Timestamp,
+        time_end: &Timestamp,
+    ) -> Result<Vec<datapoint::Datapoint>>
+    where
+        Self: Sized,
+    {
+        let series = match self.find_series(metric, tags)? {
+            Some(series) => series,
+            None => return Ok(vec![]),
+        };
 
-        while current_time < *time_end {
-            let batch = self.get_datapoints_in_bucket(metric, tags, &current_time)?;
-            let filtered = batch.iter().filter(|e| e.time >= *time_start && e.time <= *time_end).cloned();
-            results.extend(filtered);
-            current_time += Duration::from_secs(SECS_IN_MINUTE);
+        let mut results = Vec::<datapoint::Datapoint>::new();
+        for point in self.scan_series(&series, time_start, time_end) {
+            let (time, value) = point?;
+            results.push(datapoint::Datapoint {
+                metric: series.metric.clone(),
+                tags: series.tags.clone(),
+                value,
+                time,
+            });
         }
 
         Ok(results)
     }
 }
+
+#[test]
+fn test_data_key() {
+    let buckets = [MIN_TIMESTAMP, -2208988800, -60, 0, 60, 1650000000, 253402300740];
+    for bucket in buckets {
+        assert_eq!(parse_data_key(&data_key(7, bucket)), Some((7, bucket)));
+    }
+    for pair in buckets.windows(2) {
+        assert!(data_key(7, pair[0]) < data_key(7, pair[1]));
+    }
+    assert_eq!(parse_data_key("metric#host:a"), None);
+}
@@ -1,9 +1,14 @@
 use anyhow::Result;
-use rocksdb::{DBCompressionType, Options, DB};
+use rocksdb::{DBCompressionType, Direction, IteratorMode, Options, WriteBatch, DB};
 use std::str;
 
+use super::cardinality::Limits;
+
+use super::DB as _;
+
 pub struct RocksDB {
     db: DB,
+    limits: Limits,
 }
 
 impl RocksDB {
@@ -11,8 +16,17 @@ impl RocksDB {
         let mut options = Options::default();
         options.set_compression_type(DBCompressionType::Zstd);
         options.create_if_missing(true);
-        let db = DB::open(&options, path)?;
-        Ok(RocksDB { db: db })
+        let db = RocksDB {
+            db: DB::open(&options, path)?,
+            limits: Limits::default(),
+        };
+        db.check_format_version()?;
+        Ok(db)
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
     }
 }
 
@@ -25,4 +39,34 @@ impl super::DB for RocksDB {
     fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
         Ok(self.db.get(key)?)
     }
+
+    fn limits(&self) -> Limits {
+        self.limits.clone()
+    }
+
+    fn scan_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        let iter = self
+            .db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Forward));
+        Box::new(iter.map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), value.into_vec())))
+    }
+
+    fn scan_back_from(&self, start: &str) -> Box<dyn Iterator<Item = (String, Vec<u8>)> + '_> {
+        let iter = self
+            .db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Reverse));
+        Box::new(iter.map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), value.into_vec())))
+    }
+
+    fn write_batch(&self, batch: Vec<(String, Option<Vec<u8>>)>) -> Result<()> {
+        let mut write_batch = WriteBatch::default();
+        for (key, val) in batch {
+            match val {
+                Some(val) => write_batch.put(key, val),
+                None => write_batch.delete(key),
+            }
+        }
+        self.db.write(write_batch)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::cmp;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use super::datapoint::Datapoint;
+use super::{data_key, parse_data_key, Timestamp, DB, MIN_TIMESTAMP};
+
+/// A stored series: the metric and tag set a client wrote, together with the
+/// id its data buckets are keyed by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub id: u64,
+    pub metric: String,
+    pub tags: HashMap<String, String>,
+}
+
+impl Series {
+    pub fn key_string(&self) -> String {
+        Datapoint::key_string(&self.metric, &self.tags)
+    }
+}
+
+/// Iterator over the points of a single series within a time range.
+///
+/// Buckets are read in key order, or in reverse for the newest points first,
+/// and only one is decoded at a time, so memory use does not depend on the
+/// length of the range being scanned.
+pub struct SeriesScan<'a, D: DB> {
+    buckets: Box<dyn Iterator<Item = (String, Vec<u8>)> + 'a>,
+    // The bucket key the scan stops after
+    end_key: String,
+    reverse: bool,
+    time_start: Timestamp,
+    time_end: Timestamp,
+    pending: std::vec::IntoIter<(Timestamp, f64)>,
+    db: PhantomData<&'a D>,
+}
+
+impl<'a, D: DB> SeriesScan<'a, D> {
+    pub fn new(db: &'a D, id: u64, time_start: Timestamp, time_end: Timestamp) -> Self {
+        let time_start = cmp::max(time_start, MIN_TIMESTAMP);
+        let (first_bucket, _) = db.select_time_bucket_and_offset(time_start);
+        let (last_bucket, _) = db.select_time_bucket_and_offset(time_end);
+        SeriesScan {
+            buckets: db.scan_from(&data_key(id, first_bucket)),
+            end_key: data_key(id, last_bucket),
+            reverse: false,
+            time_start,
+            time_end,
+            pending: Vec::new().into_iter(),
+            db: PhantomData,
+        }
+    }
+
+    pub fn new_rev(db: &'a D, id: u64, time_start: Timestamp, time_end: Timestamp) -> Self {
+        let time_start = cmp::max(time_start, MIN_TIMESTAMP);
+        let (first_bucket, _) = db.select_time_bucket_and_offset(time_start);
+        let (last_bucket, _) = db.select_time_bucket_and_offset(time_end);
+        SeriesScan {
+            buckets: db.scan_back_from(&data_key(id, last_bucket)),
+            end_key: data_key(id, first_bucket),
+            reverse: true,
+            time_start,
+            time_end,
+            pending: Vec::new().into_iter(),
+            db: PhantomData,
+        }
+    }
+}
+
+impl<'a, D: DB> Iterator for SeriesScan<'a, D> {
+    type Item = Result<(Timestamp, f64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((time, value)) = self.pending.next() {
+                if time < self.time_start || time > self.time_end {
+                    continue;
+                }
+                return Some(Ok((time, value)));
+            }
+
+            let (key, data) = self.buckets.next()?;
+            let past_end = if self.reverse {
+                key < self.end_key
+            } else {
+                key > self.end_key
+            };
+            if past_end {
+                self.buckets = Box::new(std::iter::empty());
+                return None;
+            }
+            let (_, time_bucket) = parse_data_key(&key)?;
+            let mut points = D::decode_bucket(&data, time_bucket);
+            if self.reverse {
+                points.reverse();
+            }
+            self.pending = points.into_iter();
+        }
+    }
+}
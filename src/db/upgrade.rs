@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use super::datapoint::Datapoint;
+use super::{data_key, Timestamp, DB, MOVE_BATCH_SIZE};
+
+/// Rewrites a database of the first key layout in place. Series keys were
+/// `metric#key:value,...` with nothing escaped and the tags in whatever order
+/// a HashMap gave, so one series may be stored under several keys and ids.
+/// Data keys were `<bucket>##<id>`. The buckets are moved first, chunk by
+/// chunk with the old keys dropped in the same batch, and the series keys
+/// are replaced last together with the returned batch, so an interrupted
+/// upgrade picks up where it stopped.
+pub fn upgrade_key_layout<D: DB + ?Sized>(db: &D) -> Result<Vec<(String, Option<Vec<u8>>)>> {
+    // The ids of each series under its new key, the lowest one kept
+    let mut series = HashMap::<String, Vec<u64>>::new();
+    let mut old_keys = Vec::new();
+    for (key, value) in db.scan_from("") {
+        if key.starts_with('#') || parse_old_data_key(&key).is_some() {
+            continue;
+        }
+        let id = match value.get(0..8) {
+            Some(id) => u64::from_le_bytes(id.try_into()?),
+            None => continue,
+        };
+        let (metric, tags) = parse_old_key(&key);
+        series.entry(Datapoint::key_string(&metric, &tags)).or_default().push(id);
+        old_keys.push(key);
+    }
+    let mut new_ids = HashMap::new();
+    for ids in series.values_mut() {
+        ids.sort_unstable();
+        for id in ids.iter() {
+            new_ids.insert(*id, ids[0]);
+        }
+    }
+
+    // Old data keys start with a digit, after every new one
+    let mut batch = Vec::new();
+    let mut pending = HashMap::<String, Vec<u8>>::new();
+    for (key, data) in db.scan_from("0").take_while(|(key, _)| key.as_str() < ":") {
+        let (time_bucket, id) = match parse_old_data_key(&key) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let time_bucket =
+            Timestamp::try_from(time_bucket).with_context(|| format!("bucket of `{}` is out of range", key))?;
+        // Buckets of series without a key keep their id
+        let target_key = data_key(*new_ids.get(&id).unwrap_or(&id), time_bucket);
+        let target = match pending.remove(&target_key) {
+            Some(target) => Some(target),
+            None => db.get(&target_key)?,
+        };
+        pending.insert(target_key, D::merge_data(target, &data, time_bucket));
+        batch.push((key, None));
+        if batch.len() + pending.len() >= MOVE_BATCH_SIZE {
+            batch.extend(pending.drain().map(|(key, data)| (key, Some(data))));
+            db.write_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    batch.extend(pending.drain().map(|(key, data)| (key, Some(data))));
+    db.write_batch(batch)?;
+
+    let mut batch: Vec<_> = old_keys.into_iter().map(|key| (key, None)).collect();
+    for (key, ids) in series {
+        batch.push((key, Some(ids[0].to_le_bytes().to_vec())));
+    }
+    Ok(batch)
+}
+
+// `<bucket>##<id>`, both in decimal
+fn parse_old_data_key(key: &str) -> Option<(u64, u64)> {
+    let (time_bucket, id) = key.split_once("##")?;
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|c| c.is_ascii_digit());
+    if !digits(time_bucket) || !digits(id) {
+        return None;
+    }
+    Some((time_bucket.parse().ok()?, id.parse().ok()?))
+}
+
+// The metric ends at the first #. A part without a : can only come from a
+// comma in a value, so it's joined back onto the value before it.
+fn parse_old_key(key: &str) -> (String, HashMap<String, String>) {
+    let (metric, tags) = key.split_once('#').unwrap_or((key, ""));
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for part in tags.split(',').filter(|part| !part.is_empty()) {
+        match (part.split_once(':'), pairs.last_mut()) {
+            (Some((key, value)), _) => pairs.push((key.to_owned(), value.to_owned())),
+            (None, Some((_, value))) => {
+                value.push(',');
+                value.push_str(part);
+            }
+            (None, None) => pairs.push((part.to_owned(), String::new())),
+        }
+    }
+    (metric.to_owned(), pairs.into_iter().collect())
+}
+
+#[test]
+fn test_upgrade_key_layout() {
+    use super::memory::MemoryDB;
+    use super::MAX_METRIC_ID_KEY;
+
+    let db = MemoryDB::default();
+    let bucket = |value, offset| MemoryDB::format_data(None, value, offset);
+    db.put(MAX_METRIC_ID_KEY, &3u64.to_le_bytes()).unwrap();
+    // The same series twice, its tags in different orders
+    db.put("cpu#host:a,dc:x", &1u64.to_le_bytes()).unwrap();
+    db.put("cpu#dc:x,host:a", &2u64.to_le_bytes()).unwrap();
+    db.put("disk#path:/a,b", &3u64.to_le_bytes()).unwrap();
+    db.put("60##1", &bucket(1.0, 0)).unwrap();
+    db.put("60##2", &bucket(2.0, 5)).unwrap();
+    db.put("120##3", &bucket(3.0, 1)).unwrap();
+
+    db.check_format_version().unwrap();
+    let series: Vec<_> = db.list_series(None).collect();
+    assert_eq!(series.len(), 2);
+    let tags = HashMap::from([("host".to_owned(), "a".to_owned()), ("dc".to_owned(), "x".to_owned())]);
+    let points = db.get_datapoints_exact("cpu", &tags, &0, &200).unwrap();
+    let points: Vec<_> = points.iter().map(|point| (point.time, point.value)).collect();
+    assert_eq!(points, vec![(60, 1.0), (65, 2.0)]);
+    let tags = HashMap::from([("path".to_owned(), "/a,b".to_owned())]);
+    assert_eq!(db.get_datapoints_exact("disk", &tags, &0, &200).unwrap()[0].time, 121);
+    assert_eq!(db.get_series_count().unwrap(), 2);
+    assert!(db.get("60##1").unwrap().is_none());
+
+    // Upgraded once only
+    db.check_format_version().unwrap();
+    assert_eq!(db.list_series(None).count(), 2);
+}
@@ -0,0 +1,213 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use crate::db::datapoint::Datapoint;
+use crate::db::escape::{escape_into, split_unescaped, unescape};
+use crate::db::series::Series;
+use crate::db::{Timestamp, DB};
+
+// A dump holds one point per line: `metric,tag=value,... value timestamp`,
+// with the timestamp in seconds since the epoch. Separators appearing inside
+// names are escaped with a backslash.
+const DUMP_SPECIAL: [char; 3] = [',', '=', ' '];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub struct ExportFilter {
+    pub metric: Option<String>,
+    pub time_start: Timestamp,
+    pub time_end: Timestamp,
+}
+
+fn encode_series(series: &Series) -> String {
+    let mut output = String::new();
+    escape_into(&mut output, &series.metric, &DUMP_SPECIAL);
+    let mut tags: Vec<_> = series.tags.iter().collect();
+    tags.sort();
+    for (key, value) in tags {
+        output.push(',');
+        escape_into(&mut output, key, &DUMP_SPECIAL);
+        output.push('=');
+        escape_into(&mut output, value, &DUMP_SPECIAL);
+    }
+    output
+}
+
+pub fn decode_line(line: &str) -> Result<Datapoint> {
+    let fields = split_unescaped(line, ' ');
+    if fields.len() != 3 {
+        bail!("expected `series value timestamp`, found {} fields", fields.len());
+    }
+
+    let mut parts = split_unescaped(fields[0], ',').into_iter();
+    let mut dp = Datapoint::default();
+    dp.metric = unescape(parts.next().unwrap_or_default());
+    if dp.metric.is_empty() {
+        bail!("missing metric name");
+    }
+    for part in parts {
+        let key_value = split_unescaped(part, '=');
+        if key_value.len() != 2 {
+            bail!("malformed tag `{}`", part);
+        }
+        dp.tags.insert(unescape(key_value[0]), unescape(key_value[1]));
+    }
+
+    dp.value = fields[1]
+        .parse()
+        .with_context(|| format!("invalid value `{}`", fields[1]))?;
+    dp.time = fields[2]
+        .parse()
+        .with_context(|| format!("invalid timestamp `{}`", fields[2]))?;
+    Ok(dp)
+}
+
+pub fn export<D: DB>(db: &D, output: &mut impl Write, filter: &ExportFilter) -> Result<u64> {
+    // Only the buckets of the series and times asked for are read
+    let mut exported = 0;
+    for series in db.list_series(filter.metric.as_deref()) {
+        let encoded = encode_series(&series);
+        for point in db.scan_series(&series, &filter.time_start, &filter.time_end) {
+            let (time, value) = point?;
+            writeln!(output, "{} {} {}", encoded, value, time)?;
+            exported += 1;
+        }
+    }
+    Ok(exported)
+}
+
+pub fn import<D: DB>(db: &D, input: impl BufRead, batch_size: usize) -> Result<u64> {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut imported = 0;
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        batch.push(decode_line(line).with_context(|| format!("line {}", number + 1))?);
+        if batch.len() >= batch_size {
+            imported += batch.len() as u64;
+            db.put_datapoints(std::mem::take(&mut batch))?;
+        }
+    }
+    imported += batch.len() as u64;
+    db.put_datapoints(batch)?;
+    Ok(imported)
+}
+
+// Exports to `path`, or standard output if there is none
+pub fn export_to<D: DB>(db: &D, path: Option<&str>, compress: bool, filter: &ExportFilter) -> Result<u64> {
+    let output: Box<dyn Write> = match path {
+        Some(path) => Box::new(File::create(path).with_context(|| format!("cannot create {}", path))?),
+        None => Box::new(io::stdout()),
+    };
+    let mut output = BufWriter::new(output);
+
+    let exported = if compress {
+        let mut encoder = zstd::stream::write::Encoder::new(&mut output, 0)?;
+        let exported = export(db, &mut encoder, filter)?;
+        encoder.finish()?;
+        exported
+    } else {
+        export(db, &mut output, filter)?
+    };
+    output.flush()?;
+    Ok(exported)
+}
+
+// Imports from `path`, or standard input if there is none. Compressed dumps
+// are recognized by the zstd frame magic.
+pub fn import_from<D: DB>(db: &D, path: Option<&str>, batch_size: usize) -> Result<u64> {
+    let input: Box<dyn io::Read> = match path {
+        Some(path) => Box::new(File::open(path).with_context(|| format!("cannot open {}", path))?),
+        None => Box::new(io::stdin()),
+    };
+    let mut input = BufReader::new(input);
+
+    if input.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::stream::read::Decoder::with_buffer(input)?;
+        import(db, BufReader::new(decoder), batch_size)
+    } else {
+        import(db, input, batch_size)
+    }
+}
+
+#[test]
+fn test_encode() {
+    use std::collections::HashMap;
+
+    let series = Series {
+        id: 1,
+        metric: "cpu load".to_owned(),
+        tags: HashMap::from([
+            ("host".to_owned(), "a,b".to_owned()),
+            ("dc".to_owned(), "x=y".to_owned()),
+        ]),
+    };
+    assert_eq!(encode_series(&series), "cpu\\ load,dc=x\\=y,host=a\\,b");
+}
+
+#[test]
+fn test_decode() {
+    use std::collections::HashMap;
+
+    let dp = decode_line("cpu\\ load,dc=x\\=y,host=a\\,b 1.5 1650000000").unwrap();
+    assert_eq!(dp.metric, "cpu load");
+    assert_eq!(
+        dp.tags,
+        HashMap::from([
+            ("host".to_owned(), "a,b".to_owned()),
+            ("dc".to_owned(), "x=y".to_owned()),
+        ])
+    );
+    assert_eq!(dp.value, 1.5);
+    assert_eq!(dp.time, 1650000000);
+
+    let dp = decode_line("m -2e-3 -62135596800").unwrap();
+    assert!(dp.tags.is_empty());
+    assert_eq!(dp.value, -2e-3);
+    assert_eq!(dp.time, -62135596800);
+
+    assert!(decode_line("m 1.5").is_err());
+    assert!(decode_line("m,host 1.5 60").is_err());
+    assert!(decode_line("m x 60").is_err());
+}
+
+#[test]
+fn test_export_import() {
+    use crate::db::memory::MemoryDB;
+
+    let db = MemoryDB::default();
+    let dump = "cpu,host=a 1.5 -90\ncpu,host=a 2 60\ncpu,host=b 3 61\nmem 4 200\n";
+    assert_eq!(import(&db, dump.as_bytes(), 2).unwrap(), 4);
+
+    let export_lines = |metric: Option<&str>, time_start, time_end| {
+        let filter = ExportFilter {
+            metric: metric.map(str::to_owned),
+            time_start,
+            time_end,
+        };
+        let mut output = Vec::new();
+        let exported = export(&db, &mut output, &filter).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count() as u64, exported);
+        output
+    };
+    let exported = export_lines(None, Timestamp::MIN, Timestamp::MAX);
+    assert_eq!(exported, dump);
+    assert_eq!(export_lines(Some("cpu"), -90, 60), "cpu,host=a 1.5 -90\ncpu,host=a 2 60\n");
+    assert_eq!(export_lines(Some("disk"), Timestamp::MIN, Timestamp::MAX), "");
+
+    // An export imported into an empty database exports the same
+    let copy = MemoryDB::default();
+    import(&copy, exported.as_bytes(), 1000).unwrap();
+    let mut output = Vec::new();
+    let filter = ExportFilter {
+        metric: None,
+        time_start: Timestamp::MIN,
+        time_end: Timestamp::MAX,
+    };
+    export(&copy, &mut output, &filter).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), exported);
+}
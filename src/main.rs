@@ -1,129 +1,253 @@
 mod db;
+mod dump;
 mod parser;
-use anyhow::Result;
-use clap::Parser;
+mod query;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
 use db::datapoint::Datapoint;
+use parser::insert::Value;
 use parser::SqlStatement;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::collections::HashMap;
-use std::ops::{Add, Sub};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::str;
-use std::time::{Duration, SystemTime};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(long)]
     database_dir: String,
+    /// Reject new series once the database has this many
+    #[clap(long)]
+    max_series: Option<u64>,
+    /// Reject new series once a metric has this many
+    #[clap(long)]
+    max_series_per_metric: Option<u64>,
+    /// Reject new series bringing a tag key of a metric past this many values
+    #[clap(long)]
+    max_tag_values: Option<u64>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-fn testing_stuff(db: &impl db::DB) -> Result<()> {
-    let time = SystemTime::now();
-    let dp = db::datapoint::Datapoint {
-        metric: "test".to_owned(),
-        value: 1.5,
-        time: time,
-        tags: HashMap::new(),
-    };
-    println!("{}", dp.to_key_string());
-    let tags = HashMap::from([
-        ("test".to_owned(), "bla".to_owned()),
-        ("xxx".to_owned(), "yyy".to_owned()),
-    ]);
-    let dp2 = db::datapoint::Datapoint {
-        metric: "metric".to_owned(),
-        value: 2.3,
-        time: time,
-        tags: tags,
-    };
-    println!("{}", dp2.to_key_string());
-
-    println!("{:?}", db.get(db::MAX_METRIC_ID_KEY)?);
-    db.put_datapoint(dp);
-    println!("{:?}", db.get(db::MAX_METRIC_ID_KEY)?);
-    db.put_datapoint(dp2);
-    println!("{:?}", db.get(db::MAX_METRIC_ID_KEY)?);
-    println!("{:?}", db.get("test#")?);
-    println!("{:?}", db.get("metric#test:bla,xxx:yyy")?);
-    println!("{:?}", db.get("metric#xxx:yyy,test:bla")?);
-
-    let bucket = (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() / 60) * 60;
-    println!("{:?}", db.get(format!("{}##{}", bucket, 1).as_ref())?);
-    println!("{:?}", db.get(format!("{}##{}", bucket, 2).as_ref())?);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write all series and points to a line-based dump
+    Export {
+        /// File to write to, standard output if not given
+        #[clap(long)]
+        output: Option<String>,
+        /// Only export series of this metric
+        #[clap(long)]
+        metric: Option<String>,
+        /// Only export points at or after this Unix time
+        #[clap(long, allow_hyphen_values = true)]
+        start: Option<i64>,
+        /// Only export points at or before this Unix time
+        #[clap(long, allow_hyphen_values = true)]
+        end: Option<i64>,
+        /// Compress the dump with zstd
+        #[clap(long)]
+        zstd: bool,
+    },
+    /// Load a dump written by export, compressed or not
+    Import {
+        /// File to read from, standard input if not given
+        #[clap(long)]
+        input: Option<String>,
+        /// Number of points written per batch
+        #[clap(long, default_value = "10000")]
+        batch_size: usize,
+    },
+}
 
-    println!("{:?}", db.get_datapoints_in_bucket("test", &HashMap::new(), &time));
-    println!("{:?}", db.get_datapoints_exact("test", &HashMap::new(), &time.sub(Duration::from_secs(3600)), &time.add(Duration::from_secs(60)))?);
-    Ok(())
+// The point of a row of INSERT ... VALUES: the number other than time is the
+// value of the metric it's for, strings are tags
+fn row_datapoint(values: HashMap<String, Value>) -> Result<Datapoint> {
+    let mut dp = Datapoint::default();
+    let mut metric: Option<String> = None;
+    for (key, value) in values {
+        match value {
+            Value::Number(time) if key == "time" => {
+                if time.fract() != 0.0 || !(i64::MIN as f64..i64::MAX as f64).contains(&time) {
+                    bail!("time must be whole seconds, found {}", time);
+                }
+                dp.time = time as i64;
+            }
+            _ if key == "time" => bail!("time must be a number"),
+            Value::Number(number) => {
+                if let Some(other) = &metric {
+                    bail!("`{}` and `{}` are both numbers, only the metric can be", other, key);
+                }
+                dp.value = number;
+                metric = Some(key);
+            }
+            Value::Str(text) => {
+                dp.tags.insert(key, text);
+            }
+        }
+    }
+    match metric {
+        Some(metric) => dp.metric = metric,
+        None => bail!("no field is a number to take as the value of a metric"),
+    }
+    Ok(dp)
 }
 
 fn run_cmd(sql: SqlStatement, db: &impl db::DB) -> Result<()> {
-    use parser::select::Operator;
+    use parser::alter::SeriesChange;
+    use parser::insert::InsertSource;
+    use parser::show::Show;
     match sql {
-        SqlStatement::Select(s) => {
-            let mut start_time = SystemTime::UNIX_EPOCH;
-            let mut end_time = SystemTime::UNIX_EPOCH.add(Duration::from_secs(u32::MAX.into()));
-            let mut tags = HashMap::<String, String>::new();
-            for c in s.conditions {
-                if c.field == "time" {
-                    let time = SystemTime::UNIX_EPOCH.add(Duration::from_secs(c.value.parse()?));
-                    match c.operator {
-                        Operator::Ge => start_time = time,
-                        Operator::Gt => start_time = time.add(Duration::from_secs(1)),
-                        Operator::Le => end_time = time,
-                        Operator::Lt => end_time = time.sub(Duration::from_secs(1)),
-                        Operator::Eq => {
-                            start_time = time;
-                            end_time = time;
-                        },
-                        Operator::Ne => {
-                            // Invalid, deal with it later
-                            end_time = SystemTime::UNIX_EPOCH;
-                        }
-                    }
-                    continue;
-                }
-                // Again, more operators not supported yet
-                if c.operator == Operator::Eq {
-                    tags.insert(c.field, c.value);
-                }
+        SqlStatement::Select(s) => query::execute(db, &s, &mut query::output::Printer),
+        SqlStatement::Insert(i) => match i.source {
+            InsertSource::Values(rows) => {
+                let points = rows.into_iter().map(row_datapoint).collect::<Result<Vec<_>>>()?;
+                db.put_datapoints(points)
             }
-            let mut results = Vec::new();
-            for field in s.fields {
-                let batch = db.get_datapoints_exact(&field, &tags, &start_time, &end_time)?;
-                results.extend(batch);
+            InsertSource::Select(select) => {
+                let mut writer = query::output::Writer::new(db, &i.table);
+                query::execute(db, &select, &mut writer)?;
+                println!("Inserted {} points", writer.finish()?);
+                Ok(())
             }
-            println!("{:?}", results);
-            Ok(())
         },
-        SqlStatement::Insert(i) => {
-            let mut dp = Datapoint::default();
-            for (key, value) in i.values {
-                match key.as_ref() {
-                    "time" => {
-                        dp.time = SystemTime::UNIX_EPOCH.add(Duration::from_secs(value.parse::<u64>()?));
-                    },
-                    s => {
-                        match value.parse::<f64>() {
-                            Ok(v) => {
-                                dp.value = v;
-                                dp.metric = s.to_owned();
-                            },
-                            Err(_) => {
-                                dp.tags.insert(s.to_owned(), value);
+        SqlStatement::RenameMetric(r) => {
+            let series: Vec<_> = db.list_series(Some(&r.from)).collect();
+            for s in &series {
+                db.move_series(s, &r.to, &s.tags)?;
+            }
+            println!("Renamed {} series", series.len());
+            Ok(())
+        }
+        SqlStatement::AlterSeries(a) => {
+            let filter = query::filter::Filter::new(&a.conditions)?;
+            let series: Vec<_> = db
+                .list_series(Some(&a.metric))
+                .filter(|s| filter.matches(&s.tags))
+                .collect();
+            // Checked for every series before any is moved
+            let mut moves = Vec::new();
+            for s in &series {
+                let mut tags = s.tags.clone();
+                match &a.change {
+                    SeriesChange::SetTags(assignments) => {
+                        for (key, value) in assignments {
+                            tags.insert(key.clone(), value.clone());
+                        }
+                    }
+                    SeriesChange::RenameTag { from, to } => {
+                        if let Some(value) = tags.remove(from) {
+                            if tags.contains_key(to) {
+                                bail!("series `{}` already has tag `{}`", s.key_string(), to);
                             }
+                            tags.insert(to.clone(), value);
                         }
                     }
                 }
+                moves.push((s, tags));
+            }
+            for (s, tags) in moves {
+                db.move_series(s, &s.metric, &tags)?;
+            }
+            println!("Altered {} series", series.len());
+            Ok(())
+        }
+        SqlStatement::Show(Show::Cardinality { limit }) => {
+            let metrics = db::cardinality::cardinality(db)?;
+            let limit = limit.unwrap_or(usize::MAX);
+            println!("Series: {}", db.get_series_count()?);
+            println!("metric\tseries");
+            for m in metrics.iter().take(limit) {
+                println!("{}\t{}", m.metric, m.series);
             }
-            return db.put_datapoint(dp);
+            let mut tags: Vec<_> = metrics
+                .iter()
+                .flat_map(|m| m.tag_values.iter().map(move |(key, values)| (&m.metric, key, *values)))
+                .collect();
+            tags.sort_by_key(|(_, _, values)| std::cmp::Reverse(*values));
+            println!("metric\ttag\tvalues");
+            for (metric, key, values) in tags.into_iter().take(limit) {
+                println!("{}\t{}\t{}", metric, key, values);
+            }
+            Ok(())
+        }
+        SqlStatement::Explain(e) => {
+            let explanation = if e.analyze {
+                query::explain::explain_analyze(db, &e.select)?
+            } else {
+                query::explain::explain(db, &e.select)?
+            };
+            print!("{}", explanation);
+            Ok(())
+        }
+        SqlStatement::Show(Show::Metrics { conditions, limit }) => {
+            let filter = query::filter::Filter::new(&conditions)?;
+            println!("metric");
+            for metric in query::schema::metrics(db, &filter, limit) {
+                println!("{}", metric);
+            }
+            Ok(())
+        }
+        SqlStatement::Show(Show::TagKeys { metric, conditions, limit }) => {
+            let filter = query::filter::Filter::new(&conditions)?;
+            println!("metric\ttag");
+            print_by_metric(query::schema::tag_keys(db, metric.as_deref(), &filter, limit));
+            Ok(())
+        }
+        SqlStatement::Show(Show::TagValues { metric, key, conditions, limit }) => {
+            let filter = query::filter::Filter::new(&conditions)?;
+            println!("metric\t{}", key);
+            print_by_metric(query::schema::tag_values(db, metric.as_deref(), &key, &filter, limit));
+            Ok(())
+        }
+        SqlStatement::Show(Show::Series { metric, conditions, limit }) => {
+            let filter = query::filter::Filter::new(&conditions)?;
+            println!("series");
+            for series in query::schema::series(db, metric.as_deref(), &filter, limit) {
+                println!("{}", series.key_string());
+            }
+            Ok(())
+        }
+    }
+}
+
+// A line per metric and name
+fn print_by_metric(names: BTreeMap<String, BTreeSet<String>>) {
+    for (metric, names) in names {
+        for name in names {
+            println!("{}\t{}", metric, name);
         }
     }
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let db = db::rocksdb::RocksDB::new(&args.database_dir)?;
+    let limits = db::cardinality::Limits {
+        max_series: args.max_series,
+        max_series_per_metric: args.max_series_per_metric,
+        max_tag_values: args.max_tag_values,
+    };
+    let db = db::rocksdb::RocksDB::new(&args.database_dir)?.with_limits(limits);
+
+    match args.command {
+        Some(Command::Export { output, metric, start, end, zstd }) => {
+            let filter = dump::ExportFilter {
+                metric,
+                time_start: start.unwrap_or(i64::MIN),
+                time_end: end.unwrap_or(i64::MAX),
+            };
+            let exported = dump::export_to(&db, output.as_deref(), zstd, &filter)?;
+            eprintln!("Exported {} points", exported);
+            return Ok(());
+        }
+        Some(Command::Import { input, batch_size }) => {
+            let imported = dump::import_from(&db, input.as_deref(), batch_size)?;
+            eprintln!("Imported {} points", imported);
+            return Ok(());
+        }
+        None => {}
+    }
 
     let mut editor = Editor::<()>::new();
     loop {
@@ -132,13 +256,21 @@ fn main() -> Result<()> {
             Ok(cmd) => {
                 editor.add_history_entry(&cmd);
                 let cmd = parser::parse(&cmd);
-                match cmd {
+                let result = match cmd {
+                    // Whatever wasn't understood would otherwise be ignored
+                    Ok((rest, _)) if !rest.trim().is_empty() => {
+                        println!("Error: could not parse `{}`", rest.trim());
+                        Ok(())
+                    }
                     Ok((_, sql)) => run_cmd(sql, &db),
                     Err(e) => {
                         println!("{:?}", e);
                         Ok(())
                     }
                 };
+                if let Err(e) = result {
+                    println!("Error: {}", e);
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -156,3 +288,21 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_alter_series() {
+    use db::DB;
+
+    let db = db::memory::MemoryDB::default();
+    let run = |sql| run_cmd(parser::parse(sql).unwrap().1, &db);
+    run("insert into cpu (cpu, hots, time) values (1, 'a', 60), (2, 'b', 60)").unwrap();
+    run("insert into cpu (cpu, hots, host, time) values (3, 'c', 'd', 60)").unwrap();
+
+    // A tag isn't renamed over another one, in any series
+    assert!(run("alter series cpu rename tag hots to host").is_err());
+    assert_eq!(db.list_series(Some("cpu")).filter(|series| series.tags.contains_key("hots")).count(), 3);
+
+    run("alter series cpu rename tag hots to host where hots != 'c'").unwrap();
+    let hosts: Vec<_> = db.list_series(Some("cpu")).map(|series| series.tags["host"].clone()).collect();
+    assert_eq!(hosts, vec!["a", "b", "d"]);
+}
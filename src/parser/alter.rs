@@ -0,0 +1,172 @@
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case};
+use nom::character::complete::{multispace0, multispace1};
+use nom::combinator::{map, opt};
+use nom::multi::many1;
+use nom::sequence::{pair, preceded, separated_pair, terminated, tuple};
+use nom::IResult;
+
+use super::lexer::{identifier, number, string};
+use super::select::{where_parser, Predicate};
+
+#[derive(Debug, PartialEq)]
+pub struct RenameMetric {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SeriesChange {
+    SetTags(Vec<(String, String)>),
+    RenameTag { from: String, to: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AlterSeries {
+    pub metric: String,
+    pub change: SeriesChange,
+    pub conditions: Vec<Predicate>,
+}
+
+// RENAME METRIC old TO new
+pub fn rename_parser(input: &str) -> IResult<&str, RenameMetric> {
+    let (input, (_, _, _, _, from, _, _, _, to)) = tuple((
+        tag_no_case("rename"),
+        multispace1,
+        tag_no_case("metric"),
+        multispace1,
+        identifier,
+        multispace1,
+        tag_no_case("to"),
+        multispace1,
+        identifier,
+    ))(input)?;
+    Ok((
+        input,
+        RenameMetric {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        },
+    ))
+}
+
+// ALTER SERIES metric SET tag = 'value', ... [WHERE ...]
+// ALTER SERIES metric RENAME TAG old TO new [WHERE ...]
+pub fn alter_parser(input: &str) -> IResult<&str, AlterSeries> {
+    let (input, (_, _, _, _, metric, _, change, conditions)) = tuple((
+        tag_no_case("alter"),
+        multispace1,
+        tag_no_case("series"),
+        multispace1,
+        identifier,
+        multispace1,
+        alt((set_parser, rename_tag_parser)),
+        opt(preceded(multispace1, where_parser)),
+    ))(input)?;
+    Ok((
+        input,
+        AlterSeries {
+            metric: metric.to_owned(),
+            change,
+            conditions: conditions.unwrap_or_default(),
+        },
+    ))
+}
+
+fn set_parser(input: &str) -> IResult<&str, SeriesChange> {
+    let assignment = separated_pair(
+        identifier,
+        tuple((multispace0, tag("="), multispace0)),
+        alt((string, map(number, str::to_owned))),
+    );
+    map(
+        preceded(
+            pair(tag_no_case("set"), multispace1),
+            many1(terminated(
+                assignment,
+                opt(tuple((multispace0, tag(","), multispace0))),
+            )),
+        ),
+        |assignments| {
+            SeriesChange::SetTags(
+                assignments
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value))
+                    .collect(),
+            )
+        },
+    )(input)
+}
+
+fn rename_tag_parser(input: &str) -> IResult<&str, SeriesChange> {
+    let (input, (_, _, _, _, from, _, _, _, to)) = tuple((
+        tag_no_case("rename"),
+        multispace1,
+        tag_no_case("tag"),
+        multispace1,
+        identifier,
+        multispace1,
+        tag_no_case("to"),
+        multispace1,
+        identifier,
+    ))(input)?;
+    Ok((
+        input,
+        SeriesChange::RenameTag {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        },
+    ))
+}
+
+#[test]
+fn test_rename() {
+    assert_eq!(
+        rename_parser("rename metric cpu to cpuload"),
+        Ok((
+            "",
+            RenameMetric {
+                from: "cpu".to_owned(),
+                to: "cpuload".to_owned()
+            }
+        ))
+    );
+    assert!(rename_parser("rename metric cpu").is_err());
+}
+
+#[test]
+fn test_alter() {
+    use super::select::{Condition, Operator};
+    assert_eq!(
+        alter_parser("alter series cpu set host = 'web1', dc = 'eu' where host = 'webl'"),
+        Ok((
+            "",
+            AlterSeries {
+                metric: "cpu".to_owned(),
+                change: SeriesChange::SetTags(vec![
+                    ("host".to_owned(), "web1".to_owned()),
+                    ("dc".to_owned(), "eu".to_owned())
+                ]),
+                conditions: vec![Predicate::Condition(Condition {
+                    field: "host".to_owned(),
+                    operator: Operator::Eq,
+                    value: "webl".to_owned()
+                })]
+            }
+        ))
+    );
+    assert_eq!(
+        alter_parser("ALTER SERIES cpu RENAME TAG hots TO host"),
+        Ok((
+            "",
+            AlterSeries {
+                metric: "cpu".to_owned(),
+                change: SeriesChange::RenameTag {
+                    from: "hots".to_owned(),
+                    to: "host".to_owned()
+                },
+                conditions: vec![]
+            }
+        ))
+    );
+}
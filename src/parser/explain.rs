@@ -0,0 +1,41 @@
+use nom::bytes::complete::tag_no_case;
+use nom::character::complete::multispace1;
+use nom::combinator::{map, opt};
+use nom::sequence::{terminated, tuple};
+use nom::IResult;
+
+use super::select::{select_parser, Select};
+
+#[derive(Debug, PartialEq)]
+pub struct Explain {
+    // Whether to run the query and report what that took too
+    pub analyze: bool,
+    pub select: Select,
+}
+
+pub fn explain_parser(input: &str) -> IResult<&str, Explain> {
+    map(
+        tuple((
+            tag_no_case("explain"),
+            multispace1,
+            opt(terminated(tag_no_case("analyze"), multispace1)),
+            select_parser,
+        )),
+        |(_, _, analyze, select)| Explain {
+            analyze: analyze.is_some(),
+            select,
+        },
+    )(input)
+}
+
+#[test]
+fn test_explain() {
+    let (unparsed, explain) = explain_parser("explain select max(cpu) from m").unwrap();
+    assert_eq!(unparsed, "");
+    assert!(!explain.analyze);
+    assert_eq!(explain.select.fields[0].expr.to_string(), "max(cpu)");
+
+    let (_, explain) = explain_parser("EXPLAIN ANALYZE select cpu from m").unwrap();
+    assert!(explain.analyze);
+    assert!(explain_parser("explain analyze").is_err());
+}
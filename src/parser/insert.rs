@@ -1,65 +1,97 @@
 use nom::branch::alt;
 use nom::bytes::complete::{tag, tag_no_case};
-use nom::character::complete::{alpha1, alphanumeric0, digit1, multispace0, multispace1};
-use nom::combinator::{opt, recognize};
-use nom::multi::many1;
-use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::character::complete::{multispace0, multispace1};
+use nom::combinator::{map, opt, verify};
+use nom::multi::{many1, separated_list1};
+use nom::sequence::{delimited, preceded, terminated, tuple};
 use nom::IResult;
 use std::collections::HashMap;
 
+use super::lexer::{float, identifier, string};
+use super::select::{select_parser, Select};
+
 #[derive(Debug, PartialEq)]
 pub struct Insert {
     pub table: String,
-    pub values: HashMap<String, String>,
+    pub source: InsertSource,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InsertSource {
+    // A point per row, each value by the field it's for
+    Values(Vec<HashMap<String, Value>>),
+    // The results of a query, written under the table
+    Select(Box<Select>),
 }
 
 pub fn insert_parser(input: &str) -> IResult<&str, Insert> {
-    let (input, (_, table, _, fields, _, _, _, values)) = tuple((
+    let (input, (_, table, _, source)) = tuple((
         tag_no_case("insert"),
         table_parser,
         multispace1,
-        field_parser,
-        multispace1,
-        tag_no_case("values"),
-        multispace1,
-        value_parser,
+        // Rows last, so that theirs is the error reported
+        alt((map(select_parser, |select| InsertSource::Select(Box::new(select))), rows_parser)),
     ))(input)?;
-    if fields.len() != values.len() {
-        return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(input, nom::error::ErrorKind::LengthValue)))
-    }
-    let mut outvalues: HashMap<String, String> = HashMap::with_capacity(fields.len());
-    for iter in fields.iter().zip(values.iter()) {
-        let (field, value) = iter;
-        outvalues.insert((*field).to_owned(), (*value).to_owned());
-    }
     Ok((
         input,
         Insert {
             table: table.to_owned(),
-            values: outvalues,
+            source,
         },
     ))
 }
 
+// (fields) VALUES (values), (values), ...
+fn rows_parser(input: &str) -> IResult<&str, InsertSource> {
+    let (input, (fields, _, _, _, rows)) = tuple((
+        field_parser,
+        multispace1,
+        tag_no_case("values"),
+        multispace1,
+        separated_list1(tuple((multispace0, tag(","), multispace0)), value_parser),
+    ))(input)?;
+    if rows.iter().any(|values| values.len() != fields.len()) {
+        return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(input, nom::error::ErrorKind::LengthValue)));
+    }
+    let rows = rows
+        .into_iter()
+        .map(|values| fields.iter().map(|field| field.to_string()).zip(values).collect())
+        .collect();
+    Ok((input, InsertSource::Values(rows)))
+}
+
+// Each field at most once, as a row holds one value per field
 fn field_parser(input: &str) -> IResult<&str, Vec<&str>> {
-    let (unparsed, fields) = delimited(
+    let fields = delimited(
         tag("("),
         many1(terminated(
-            recognize(pair(alpha1, alphanumeric0)),
+            identifier,
             opt(tuple((multispace0, tag(","), multispace0))),
         )),
         tag(")"),
-    )(input)?;
+    );
+    let (unparsed, fields) = verify(fields, |fields: &Vec<&str>| {
+        fields.iter().enumerate().all(|(i, field)| !fields[..i].contains(field))
+    })(input)?;
     Ok((unparsed, fields))
 }
 
-fn value_parser(input: &str) -> IResult<&str, Vec<&str>> {
+// A value of VALUES, which are numbers unless quoted. Bare words are taken as
+// strings too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Number(f64),
+}
+
+fn value_parser(input: &str) -> IResult<&str, Vec<Value>> {
     let (unparsed, fields) = delimited(
         tag("("),
         many1(terminated(
             alt((
-                delimited(tag("'"), recognize(pair(alpha1, alphanumeric0)), tag("'")),
-                digit1,
+                map(string, Value::Str),
+                map(verify(float, |number| number.is_finite()), Value::Number),
+                map(identifier, |word| Value::Str(word.to_owned())),
             )),
             opt(tuple((multispace0, tag(","), multispace0))),
         )),
@@ -71,7 +103,7 @@ fn value_parser(input: &str) -> IResult<&str, Vec<&str>> {
 fn table_parser(input: &str) -> IResult<&str, &str> {
     let (unparsed, table) = preceded(
         tuple((multispace1, tag_no_case("into"), multispace1)),
-        recognize(pair(alpha1, alphanumeric0)),
+        identifier,
     )(input)?;
 
     Ok((unparsed, table))
@@ -85,10 +117,10 @@ fn test_basic() {
             "",
             Insert {
                 table: "x".to_owned(),
-                values: HashMap::from([
-                    ("a".to_owned(), "c".to_owned()),
-                    ("b".to_owned(), "d".to_owned())
-                ])
+                source: InsertSource::Values(vec![HashMap::from([
+                    ("a".to_owned(), Value::Str("c".to_owned())),
+                    ("b".to_owned(), Value::Str("d".to_owned()))
+                ])])
             }
         ))
     );
@@ -102,6 +134,31 @@ fn test_basic() {
     );
 }
 
+#[test]
+fn test_rows() {
+    let (unparsed, insert) = insert_parser("insert into cpu (host, cpu, time) values ('a', 1, 60), (b, 2.5, 120)").unwrap();
+    assert_eq!(unparsed, "");
+    let rows = match insert.source {
+        InsertSource::Values(rows) => rows,
+        source => panic!("expected values, found {:?}", source),
+    };
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["host"], Value::Str("a".to_owned()));
+    assert_eq!(rows[1]["host"], Value::Str("b".to_owned()));
+    assert_eq!(rows[1]["cpu"], Value::Number(2.5));
+    assert!(insert_parser("insert into cpu (cpu, time) values (1, 60), (2)").is_err());
+    assert!(insert_parser("insert into cpu (cpu, cpu, time) values (1, 2, 60)").is_err());
+
+    let (unparsed, insert) =
+        insert_parser("insert into cpu_1h select mean(cpu) from m group by time(1h), host").unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!(insert.table, "cpu_1h");
+    match insert.source {
+        InsertSource::Select(select) => assert_eq!(select.fields[0].expr.to_string(), "mean(cpu)"),
+        source => panic!("expected a select, found {:?}", source),
+    }
+}
+
 #[test]
 fn test_fields() {
     assert_eq!(field_parser("(xxx, yyy)"), Ok(("", vec!("xxx", "yyy"))));
@@ -111,12 +168,36 @@ fn test_fields() {
         Ok(("", vec!("a1a", "b0b", "c2c")))
     );
     assert_eq!(field_parser("(a1a)"), Ok(("", vec!("a1a"))));
+    assert!(field_parser("(host, cpu, host)").is_err());
+    assert!(field_parser("(host, \"host\")").is_err());
+}
+
+#[test]
+fn test_values() {
+    let text = |text: &str| Value::Str(text.to_owned());
+    assert_eq!(value_parser("(10, 'abc')"), Ok(("", vec![Value::Number(10.0), text("abc")])));
+    assert_eq!(value_parser("(-2208988800)"), Ok(("", vec![Value::Number(-2208988800.0)])));
+    assert_eq!(
+        value_parser("(21.5, -3, 'eu-west-1', \"it\\'s\", web_01, '')"),
+        Ok((
+            "",
+            vec![Value::Number(21.5), Value::Number(-3.0), text("eu-west-1"), text("it's"), text("web_01"), text("")]
+        ))
+    );
+    // Quoted numbers stay strings, and so do words a float could be read from
+    assert_eq!(
+        value_parser("('10', '1e3', inf, nan)"),
+        Ok(("", vec![text("10"), text("1e3"), text("inf"), text("nan")]))
+    );
+    assert!(value_parser("(1e400)").is_err());
 }
 
 #[test]
 fn test_table() {
     assert_eq!(table_parser(" into table1"), Ok(("", "table1")));
     assert_eq!(table_parser(" INTO table2"), Ok(("", "table2")));
+    assert_eq!(table_parser(" into \"cpu.load\""), Ok(("", "cpu.load")));
+    assert_eq!(table_parser(" into `disk io`"), Ok(("", "disk io")));
     assert_eq!(
         table_parser(" into table1 where XX"),
         Ok((" where XX", "table1"))
@@ -0,0 +1,94 @@
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_till1};
+use nom::character::complete::{alpha1, alphanumeric1, char};
+use nom::combinator::{map_opt, recognize};
+use nom::multi::many0;
+use nom::number::complete::recognize_float;
+use nom::sequence::{delimited, pair};
+use nom::IResult;
+
+// Tokens shared by every statement
+
+// A metric, tag or table name: letters, digits and underscores not starting
+// with a digit, or anything else between double quotes or backticks
+pub fn identifier(input: &str) -> IResult<&str, &str> {
+    alt((
+        recognize(pair(
+            alt((alpha1, tag("_"))),
+            many0(alt((alphanumeric1, tag("_")))),
+        )),
+        delimited(char('"'), take_till1(|c| c == '"'), char('"')),
+        delimited(char('`'), take_till1(|c| c == '`'), char('`')),
+    ))(input)
+}
+
+// A number as written, with an optional sign, fraction and exponent
+pub fn number(input: &str) -> IResult<&str, &str> {
+    recognize_float(input)
+}
+
+pub fn float(input: &str) -> IResult<&str, f64> {
+    map_opt(number, |number: &str| number.parse().ok())(input)
+}
+
+// Text between single or double quotes. A backslash escapes the next
+// character, with \n and \t standing for a newline and a tab.
+pub fn string(input: &str) -> IResult<&str, String> {
+    let quote = match input.chars().next() {
+        Some(quote) if quote == '\'' || quote == '"' => quote,
+        _ => return error(input),
+    };
+    let mut output = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => output.push('\n'),
+                Some((_, 't')) => output.push('\t'),
+                Some((_, escaped)) => output.push(escaped),
+                None => break,
+            },
+            c if c == quote => return Ok((&input[i + 1..], output)),
+            c => output.push(c),
+        }
+    }
+    error(input)
+}
+
+fn error<T>(input: &str) -> IResult<&str, T> {
+    Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+        input,
+        nom::error::ErrorKind::Char,
+    )))
+}
+
+#[test]
+fn test_identifier() {
+    assert_eq!(identifier("web_01 x"), Ok((" x", "web_01")));
+    assert_eq!(identifier("_tmp"), Ok(("", "_tmp")));
+    assert_eq!(identifier("\"cpu.load-1\""), Ok(("", "cpu.load-1")));
+    assert_eq!(identifier("`disk io`"), Ok(("", "disk io")));
+    assert!(identifier("1st").is_err());
+    assert!(identifier("\"\"").is_err());
+}
+
+#[test]
+fn test_number() {
+    assert_eq!(float("21.5"), Ok(("", 21.5)));
+    assert_eq!(float("-3"), Ok(("", -3.0)));
+    assert_eq!(float("1.5e-3)"), Ok((")", 1.5e-3)));
+    assert_eq!(number("-2208988800"), Ok(("", "-2208988800")));
+    assert!(float("inf").is_err());
+    assert!(float("x").is_err());
+}
+
+#[test]
+fn test_string() {
+    assert_eq!(string("'eu-west-1' and"), Ok((" and", "eu-west-1".to_owned())));
+    assert_eq!(string("\"it's\""), Ok(("", "it's".to_owned())));
+    assert_eq!(string("'it\\'s a \\\\ \\n'"), Ok(("", "it's a \\ \n".to_owned())));
+    assert_eq!(string("'Zürich 東京'"), Ok(("", "Zürich 東京".to_owned())));
+    assert_eq!(string("''"), Ok(("", String::new())));
+    assert!(string("'open").is_err());
+    assert!(string("bare").is_err());
+}
@@ -1,22 +1,38 @@
+use alter::{alter_parser, rename_parser, AlterSeries, RenameMetric};
+use explain::{explain_parser, Explain};
 use insert::{insert_parser, Insert};
 use nom::branch::alt;
 use nom::combinator::map;
 use nom::IResult;
 use select::{select_parser, Select};
+use show::{show_parser, Show};
 
+pub mod alter;
+pub mod explain;
 pub mod insert;
+pub mod lexer;
 pub mod select;
+pub mod show;
+pub mod time;
 
 #[derive(Debug, PartialEq)]
 pub enum SqlStatement {
     Select(Select),
     Insert(Insert),
+    RenameMetric(RenameMetric),
+    AlterSeries(AlterSeries),
+    Show(Show),
+    Explain(Explain),
 }
 
 pub fn parse(input: &str) -> IResult<&str, SqlStatement> {
     let (input, sql) = alt((
         map(select_parser, |select| SqlStatement::Select(select)),
         map(insert_parser, |insert| SqlStatement::Insert(insert)),
+        map(rename_parser, SqlStatement::RenameMetric),
+        map(alter_parser, SqlStatement::AlterSeries),
+        map(show_parser, SqlStatement::Show),
+        map(explain_parser, SqlStatement::Explain),
     ))(input)?;
     Ok((input, sql))
 }
@@ -29,9 +45,10 @@ fn test_basic() {
         Ok((
             "",
             SqlStatement::Select(Select {
-                table: "y".to_owned(),
-                fields: vec!("x".to_owned()),
+                table: select::Table::Name("y".to_owned()),
+                fields: vec![select::Expr::Field("x".to_owned()).into()],
                 conditions: vec![],
+                ..Default::default()
             })
         ))
     );
@@ -39,10 +56,10 @@ fn test_basic() {
         parse("insert into x (y,z) values (a,b)"),
         Ok(("", SqlStatement::Insert(Insert {
             table: "x".to_owned(),
-            values: HashMap::from([
-                ("y".to_owned(), "a".to_owned()),
-                ("z".to_owned(), "b".to_owned())
-            ])
+            source: insert::InsertSource::Values(vec![HashMap::from([
+                ("y".to_owned(), insert::Value::Str("a".to_owned())),
+                ("z".to_owned(), insert::Value::Str("b".to_owned()))
+            ])])
         })))
     );
     assert_eq!(
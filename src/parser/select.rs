@@ -1,19 +1,220 @@
 use nom::branch::alt;
 use nom::bytes::complete::{tag, tag_no_case};
-use nom::character::complete::{alpha1, alphanumeric0, digit1, multispace0, multispace1};
-use nom::combinator::{opt, recognize};
-use nom::multi::many1;
-use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::character::complete::{digit1, multispace0, multispace1, satisfy};
+use nom::combinator::{cut, map, map_opt, map_res, not, opt, peek, verify};
+use nom::multi::{many0, many1, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
+use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+use super::lexer::{float, identifier, number, string};
+use super::time::{duration_nanos_parser, time_expr_parser, TimeExpr, NANOS_IN_SECOND};
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Select {
-    pub table: String,
-    pub fields: Vec<String>,
-    pub conditions: Vec<Condition>,
+    pub table: Table,
+    pub fields: Vec<SelectField>,
+    // All of these must hold, as if joined with AND
+    pub conditions: Vec<Predicate>,
+    pub group_by: GroupBy,
+    pub fill: Fill,
+    pub order: Order,
+    // Seconds the points are read from before the time range, then moved
+    // forward into it, as in OFFSET 7d
+    pub shift: i64,
+    // Rows per series, then series per query
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub slimit: Option<usize>,
+    pub soffset: usize,
+}
+
+// What a SELECT reads from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Table {
+    // The name results are reported under, metrics are read from any table
+    Name(String),
+    // The results of another SELECT, each column of each result series read
+    // as a series of the metric named after the column
+    Subquery(Box<Select>),
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Table::Name(String::new())
+    }
+}
+
+impl Table {
+    // The name of the innermost table
+    pub fn name(&self) -> &str {
+        match self {
+            Table::Name(name) => name,
+            Table::Subquery(select) => select.table.name(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GroupBy {
+    pub time: Option<TimeWindow>,
+    pub tags: TagGrouping,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum TagGrouping {
+    #[default]
+    None,
+    Keys(Vec<String>),
+    // GROUP BY *, every distinct tag set on its own
+    All,
+}
+
+enum GroupByItem {
+    Time(TimeWindow),
+    Tag(String),
+    All,
+}
+
+// What empty windows of GROUP BY time hold. By default they are left out.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Fill {
+    Null,
+    #[default]
+    None,
+    Value(f64),
+    Previous,
+    Linear,
+}
+
+// Windows start at multiples of `interval` seconds since the epoch, moved
+// forward by `offset` seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeWindow {
+    pub interval: i64,
+    pub offset: i64,
+}
+
+// An expression of the SELECT list and the name its column is given
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectField {
+    pub expr: Expr,
+    pub alias: Option<String>,
+}
+
+impl From<Expr> for SelectField {
+    fn from(expr: Expr) -> Self {
+        SelectField { expr, alias: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    // A metric, or * for all of them
+    Field(String),
+    // Every metric matching the regular expression, as in /^cpu_/
+    Regex(String),
+    // Function names are lowercased, arguments are checked when executing
+    Call { function: String, args: Vec<Expr> },
+    // A function argument in seconds, as in derivative(x, 1m)
+    Duration(i64),
+    // A function argument, as in percentile(x, 99), or a constant
+    Number(f64),
+    Binary {
+        operator: BinaryOperator,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Negate(Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+impl BinaryOperator {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Add | BinaryOperator::Subtract => 1,
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 2,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Modulo => "%",
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Field(name) => write!(f, "{}", name),
+            Expr::Regex(pattern) => write!(f, "/{}/", pattern.replace('/', "\\/")),
+            Expr::Call { function, args } => {
+                write!(f, "{}(", function)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Duration(seconds) => {
+                // In the largest unit that divides it
+                let units = [(604800, "w"), (86400, "d"), (3600, "h"), (60, "m")];
+                match units.iter().find(|(unit, _)| *seconds != 0 && seconds % unit == 0) {
+                    Some((unit, name)) => write!(f, "{}{}", seconds / unit, name),
+                    None => write!(f, "{}s", seconds),
+                }
+            }
+            Expr::Number(number) => write!(f, "{}", number),
+            // With the parentheses needed to read back the same
+            Expr::Binary { operator, left, right } => {
+                let precedence = |expr: &Expr| match expr {
+                    Expr::Binary { operator, .. } => operator.precedence(),
+                    _ => u8::MAX,
+                };
+                if precedence(left) < operator.precedence() {
+                    write!(f, "({})", left)?;
+                } else {
+                    write!(f, "{}", left)?;
+                }
+                write!(f, " {} ", operator.symbol())?;
+                if precedence(right) <= operator.precedence() {
+                    write!(f, "({})", right)
+                } else {
+                    write!(f, "{}", right)
+                }
+            }
+            Expr::Negate(inner) => match **inner {
+                Expr::Field(_) | Expr::Call { .. } => write!(f, "-{}", inner),
+                _ => write!(f, "-({})", inner),
+            },
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Operator {
     Eq,
     Ge,
@@ -21,89 +222,521 @@ pub enum Operator {
     Le,
     Lt,
     Ne,
+    // =~ and !~, the value is a regular expression
+    Match,
+    NotMatch,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Condition {
     pub field: String,
     pub operator: Operator,
     pub value: String,
 }
 
-pub fn select_parser(input: &str) -> IResult<&str, Select> {
-    let (input, (_, _, fields, table, conditions)) = tuple((
-        tag_no_case("select"),
-        multispace1,
-        field_parser,
-        table_parser,
-        opt(preceded(multispace1, where_parser)),
-    ))(input)?;
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    // On a tag, or time
+    Condition(Condition),
+    // On the value of each point
+    Value { operator: Operator, value: f64 },
+    Time { operator: Operator, time: TimeExpr },
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
 
-    let mut outfields: Vec<String> = Vec::with_capacity(fields.len());
-    for field in fields {
-        outfields.push(field.to_owned());
+impl Predicate {
+    // The terms of a chain of ANDs, in order
+    fn into_conjuncts(self, output: &mut Vec<Predicate>) {
+        match self {
+            Predicate::And(left, right) => {
+                left.into_conjuncts(output);
+                right.into_conjuncts(output);
+            }
+            other => output.push(other),
+        }
     }
+}
+
+pub fn select_parser(input: &str) -> IResult<&str, Select> {
+    let (input, (_, _, fields, table, conditions, group_by, fill, order, shift, limit, late_shift, offset, slimit, soffset)) =
+        tuple((
+            tag_no_case("select"),
+            multispace1,
+            field_parser,
+            table_parser,
+            opt(preceded(multispace1, where_parser)),
+            opt(preceded(multispace1, group_by_parser)),
+            opt(preceded(multispace1, fill_parser)),
+            opt(preceded(multispace1, order_by_parser)),
+            opt(shift_parser),
+            opt(count_parser("limit")),
+            opt(shift_parser),
+            opt(count_parser("offset")),
+            opt(count_parser("slimit")),
+            opt(count_parser("soffset")),
+        ))(input)?;
+
     let conds = match conditions {
         Some(vec) => vec,
         None => vec![],
     };
+    // OFFSET 7d may go before or after LIMIT, but only once
+    let shift = match (shift, late_shift) {
+        (Some(_), Some(_)) => {
+            return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                input,
+                nom::error::ErrorKind::Verify,
+            )))
+        }
+        (shift, late_shift) => shift.or(late_shift),
+    };
 
     Ok((
         input,
         Select {
-            table: table.to_owned(),
-            fields: outfields,
+            table,
+            fields,
             conditions: conds,
+            group_by: group_by.unwrap_or_default(),
+            fill: fill.unwrap_or_default(),
+            order: order.unwrap_or_default(),
+            shift: shift.unwrap_or(0),
+            limit,
+            offset: offset.unwrap_or(0),
+            slimit,
+            soffset: soffset.unwrap_or(0),
         },
     ))
 }
 
-fn field_parser(input: &str) -> IResult<&str, Vec<&str>> {
+fn field_parser(input: &str) -> IResult<&str, Vec<SelectField>> {
     let (unparsed, fields) = many1(terminated(
-        alt((tag("*"), recognize(pair(alpha1, alphanumeric0)))),
+        map(
+            pair(
+                expr_parser,
+                opt(preceded(tuple((multispace1, tag_no_case("as"), multispace1)), identifier)),
+            ),
+            |(expr, alias)| SelectField {
+                expr,
+                alias: alias.map(str::to_owned),
+            },
+        ),
         opt(tuple((multispace0, tag(","), multispace0))),
     ))(input)?;
     Ok((unparsed, fields))
 }
 
-fn table_parser(input: &str) -> IResult<&str, &str> {
+// Sums of products of operands, as usual
+fn expr_parser(input: &str) -> IResult<&str, Expr> {
+    binary_parser(
+        input,
+        alt((
+            map(tag("+"), |_| BinaryOperator::Add),
+            map(tag("-"), |_| BinaryOperator::Subtract),
+        )),
+        term_parser,
+    )
+}
+
+fn term_parser(input: &str) -> IResult<&str, Expr> {
+    binary_parser(
+        input,
+        alt((
+            map(tag("*"), |_| BinaryOperator::Multiply),
+            map(tag("/"), |_| BinaryOperator::Divide),
+            map(tag("%"), |_| BinaryOperator::Modulo),
+        )),
+        unary_parser,
+    )
+}
+
+// Operands joined by operators of the same precedence, from left to right
+fn binary_parser<'a>(
+    input: &'a str,
+    operator: impl FnMut(&'a str) -> IResult<&'a str, BinaryOperator>,
+    mut operand: impl FnMut(&'a str) -> IResult<&'a str, Expr>,
+) -> IResult<&'a str, Expr> {
+    let (unparsed, first) = operand(input)?;
+    let (unparsed, rest) = many0(pair(delimited(multispace0, operator, multispace0), operand))(unparsed)?;
+    let expr = rest.into_iter().fold(first, |left, (operator, right)| Expr::Binary {
+        operator,
+        left: Box::new(left),
+        right: Box::new(right),
+    });
+    Ok((unparsed, expr))
+}
+
+fn unary_parser(input: &str) -> IResult<&str, Expr> {
+    alt((
+        operand_parser,
+        map(preceded(pair(tag("-"), multispace0), unary_parser), |expr| {
+            Expr::Negate(Box::new(expr))
+        }),
+    ))(input)
+}
+
+fn operand_parser(input: &str) -> IResult<&str, Expr> {
+    alt((
+        delimited(pair(tag("("), multispace0), expr_parser, pair(multispace0, tag(")"))),
+        call_parser,
+        map(regex_parser, Expr::Regex),
+        map(duration_parser, Expr::Duration),
+        map(float, Expr::Number),
+        map(alt((tag("*"), identifier)), |field: &str| {
+            Expr::Field(field.to_owned())
+        }),
+    ))(input)
+}
+
+fn call_parser(input: &str) -> IResult<&str, Expr> {
+    let (unparsed, (function, _, args, _)) = tuple((
+        identifier,
+        pair(tag("("), multispace0),
+        separated_list1(tuple((multispace0, tag(","), multispace0)), expr_parser),
+        pair(multispace0, tag(")")),
+    ))(input)?;
+    Ok((
+        unparsed,
+        Expr::Call {
+            function: function.to_lowercase(),
+            args,
+        },
+    ))
+}
+
+fn table_parser(input: &str) -> IResult<&str, Table> {
     let (unparsed, table) = preceded(
         tuple((multispace1, tag_no_case("from"), multispace1)),
-        recognize(pair(alpha1, alphanumeric0)),
+        alt((
+            map(identifier, |name| Table::Name(name.to_owned())),
+            map(
+                delimited(pair(tag("("), multispace0), select_parser, pair(multispace0, tag(")"))),
+                |select| Table::Subquery(Box::new(select)),
+            ),
+        )),
     )(input)?;
     Ok((unparsed, table))
 }
 
-// for now only AND is supported
-fn where_parser(input: &str) -> IResult<&str, Vec<Condition>> {
-    let (unparsed, (_, _, conditions)) = tuple((
-        tag_no_case("where"),
+// GROUP BY followed by any of time(...), tag keys or *
+fn group_by_parser(input: &str) -> IResult<&str, GroupBy> {
+    let (unparsed, (_, _, _, _, items)) = tuple((
+        tag_no_case("group"),
         multispace1,
-        many1(terminated(
-            condition_parser,
-            opt(delimited(multispace1, tag_no_case("and"), multispace1)),
+        tag_no_case("by"),
+        multispace1,
+        separated_list1(
+            tuple((multispace0, tag(","), multispace0)),
+            alt((
+                map(time_window_parser, GroupByItem::Time),
+                map(tag("*"), |_| GroupByItem::All),
+                // `time` is reserved, so a malformed time(...) is not a tag
+                map(
+                    verify(identifier, |key: &str| {
+                        !key.eq_ignore_ascii_case("time")
+                    }),
+                    |key: &str| GroupByItem::Tag(key.to_owned()),
+                ),
+            )),
+        ),
+    ))(input)?;
+
+    let mut group_by = GroupBy::default();
+    for item in items {
+        match item {
+            GroupByItem::Time(window) if group_by.time.is_none() => group_by.time = Some(window),
+            GroupByItem::Time(_) => {
+                return Err(nom::Err::Failure(nom::error::ParseError::from_error_kind(
+                    input,
+                    nom::error::ErrorKind::Verify,
+                )))
+            }
+            GroupByItem::All => group_by.tags = TagGrouping::All,
+            GroupByItem::Tag(key) => match &mut group_by.tags {
+                TagGrouping::None => group_by.tags = TagGrouping::Keys(vec![key]),
+                TagGrouping::Keys(keys) => keys.push(key),
+                TagGrouping::All => {}
+            },
+        }
+    }
+    Ok((unparsed, group_by))
+}
+
+fn fill_parser(input: &str) -> IResult<&str, Fill> {
+    delimited(
+        pair(tag_no_case("fill"), pair(tag("("), multispace0)),
+        alt((
+            map(tag_no_case("null"), |_| Fill::Null),
+            map(tag_no_case("none"), |_| Fill::None),
+            map(tag_no_case("previous"), |_| Fill::Previous),
+            map(tag_no_case("linear"), |_| Fill::Linear),
+            map(verify(float, |value| value.is_finite()), Fill::Value),
+        )),
+        pair(multispace0, tag(")")),
+    )(input)
+}
+
+// Only time can be ordered by
+fn order_by_parser(input: &str) -> IResult<&str, Order> {
+    preceded(
+        tuple((
+            tag_no_case("order"),
+            multispace1,
+            tag_no_case("by"),
+            multispace1,
+            tag_no_case("time"),
         )),
+        map(
+            opt(preceded(
+                multispace1,
+                alt((
+                    map(tag_no_case("asc"), |_| Order::Asc),
+                    map(tag_no_case("desc"), |_| Order::Desc),
+                )),
+            )),
+            Option::unwrap_or_default,
+        ),
+    )(input)
+}
+
+// OFFSET with a duration rather than a count of rows, as in OFFSET 7d
+fn shift_parser(input: &str) -> IResult<&str, i64> {
+    preceded(
+        tuple((multispace1, tag_no_case("offset"), multispace1)),
+        duration_parser,
+    )(input)
+}
+
+// `keyword n`, as in LIMIT 10. A unit after the digits makes it a duration
+// instead, which this fails on.
+fn count_parser<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, usize> {
+    preceded(
+        tuple((multispace1, tag_no_case(keyword), multispace1)),
+        terminated(
+            map_res(digit1, |digits: &str| digits.parse()),
+            not(satisfy(char::is_alphabetic)),
+        ),
+    )
+}
+
+// time(interval) or time(interval, offset)
+fn time_window_parser(input: &str) -> IResult<&str, TimeWindow> {
+    let (unparsed, (_, _, interval, offset, _)) = tuple((
+        tag_no_case("time"),
+        pair(tag("("), multispace0),
+        verify(duration_parser, |interval| *interval > 0),
+        opt(preceded(tuple((multispace0, tag(","), multispace0)), duration_parser)),
+        pair(multispace0, tag(")")),
     ))(input)?;
+    Ok((
+        unparsed,
+        TimeWindow {
+            interval,
+            offset: offset.unwrap_or(0),
+        },
+    ))
+}
+
+// A duration in seconds, e.g. 5m
+pub fn duration_parser(input: &str) -> IResult<&str, i64> {
+    // Points are stored by the second, so only whole seconds make sense
+    map_opt(duration_nanos_parser, |nanos| {
+        if nanos % NANOS_IN_SECOND != 0 {
+            return None;
+        }
+        i64::try_from(nanos / NANOS_IN_SECOND).ok()
+    })(input)
+}
 
+// Conditions combined with AND, OR, NOT and parentheses, binding in that order.
+// The outermost ANDs are split into a list.
+pub fn where_parser(input: &str) -> IResult<&str, Vec<Predicate>> {
+    let (unparsed, predicate) = preceded(pair(tag_no_case("where"), multispace1), or_parser)(input)?;
+    let mut conditions = Vec::new();
+    predicate.into_conjuncts(&mut conditions);
     Ok((unparsed, conditions))
 }
 
-fn condition_parser(input: &str) -> IResult<&str, Condition> {
-    let (unparsed, (field, _, operator, _, value)) = tuple((
-        recognize(pair(alpha1, alphanumeric0)),
+fn or_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (first, rest)) = pair(
+        and_parser,
+        many0(preceded(delimited(multispace1, tag_no_case("or"), multispace1), and_parser)),
+    )(input)?;
+    let predicate = rest
+        .into_iter()
+        .fold(first, |left, right| Predicate::Or(Box::new(left), Box::new(right)));
+    Ok((unparsed, predicate))
+}
+
+fn and_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (first, rest)) = pair(
+        not_parser,
+        many0(preceded(delimited(multispace1, tag_no_case("and"), multispace1), not_parser)),
+    )(input)?;
+    let predicate = rest
+        .into_iter()
+        .fold(first, |left, right| Predicate::And(Box::new(left), Box::new(right)));
+    Ok((unparsed, predicate))
+}
+
+fn not_parser(input: &str) -> IResult<&str, Predicate> {
+    alt((
+        map(
+            preceded(pair(tag_no_case("not"), alt((multispace1, peek(tag("("))))), not_parser),
+            |predicate| Predicate::Not(Box::new(predicate)),
+        ),
+        delimited(
+            pair(tag("("), multispace0),
+            or_parser,
+            pair(multispace0, tag(")")),
+        ),
+        time_between_parser,
+        between_parser,
+        in_parser,
+        time_condition_parser,
+        value_condition_parser,
+        map(condition_parser, Predicate::Condition),
+    ))(input)
+}
+
+// `value` is reserved for the value of the points, it is not a tag: once
+// compared, anything but a number is an error
+fn value_condition_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (_, _, operator, value)) = tuple((
+        tag_no_case("value"),
         multispace0,
-        alt((
-            tag("="),
-            tag(">="),
-            tag("<="),
-            tag("!="),
-            tag(">"),
-            tag("<"),
-        )),
+        comparison_parser,
+        cut(preceded(multispace0, float)),
+    ))(input)?;
+    Ok((unparsed, Predicate::Value { operator, value }))
+}
+
+// Like `value`, `time` is not a tag
+fn time_condition_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (_, _, operator, _, time)) = tuple((
+        tag_no_case("time"),
+        multispace0,
+        comparison_parser,
+        multispace0,
+        time_expr_parser,
+    ))(input)?;
+    Ok((unparsed, Predicate::Time { operator, time }))
+}
+
+fn time_between_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (_, _, low, _, high)) = tuple((
+        tag_no_case("time"),
+        delimited(multispace1, tag_no_case("between"), multispace1),
+        time_expr_parser,
+        delimited(multispace1, tag_no_case("and"), multispace1),
+        time_expr_parser,
+    ))(input)?;
+    let low = Predicate::Time {
+        operator: Operator::Ge,
+        time: low,
+    };
+    let high = Predicate::Time {
+        operator: Operator::Le,
+        time: high,
+    };
+    Ok((unparsed, Predicate::And(Box::new(low), Box::new(high))))
+}
+
+fn comparison_parser(input: &str) -> IResult<&str, Operator> {
+    alt((
+        map(tag(">="), |_| Operator::Ge),
+        map(tag("<="), |_| Operator::Le),
+        map(tag("!="), |_| Operator::Ne),
+        map(tag("="), |_| Operator::Eq),
+        map(tag(">"), |_| Operator::Gt),
+        map(tag("<"), |_| Operator::Lt),
+    ))(input)
+}
+
+// `field BETWEEN low AND high`, both ends included
+fn between_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (field, _, low, _, high)) = tuple((
+        identifier,
+        delimited(multispace1, tag_no_case("between"), multispace1),
+        value_parser,
+        delimited(multispace1, tag_no_case("and"), multispace1),
+        value_parser,
+    ))(input)?;
+
+    let bound = |operator, value: String| {
+        if field.eq_ignore_ascii_case("value") {
+            let value = value.parse().ok().filter(|value: &f64| value.is_finite())?;
+            Some(Predicate::Value { operator, value })
+        } else {
+            Some(Predicate::Condition(Condition {
+                field: field.to_owned(),
+                operator,
+                value,
+            }))
+        }
+    };
+    match (bound(Operator::Ge, low), bound(Operator::Le, high)) {
+        (Some(low), Some(high)) => Ok((unparsed, Predicate::And(Box::new(low), Box::new(high)))),
+        _ => Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+            input,
+            nom::error::ErrorKind::Float,
+        ))),
+    }
+}
+
+// `tag IN ('a', 'b')` is `tag = 'a' OR tag = 'b'`, and NOT IN its negation
+fn in_parser(input: &str) -> IResult<&str, Predicate> {
+    let (unparsed, (field, negated, _, _, values, _)) = tuple((
+        identifier,
+        opt(preceded(multispace1, tag_no_case("not"))),
+        delimited(multispace1, tag_no_case("in"), multispace0),
+        pair(tag("("), multispace0),
+        separated_list1(tuple((multispace0, tag(","), multispace0)), value_parser),
+        pair(multispace0, tag(")")),
+    ))(input)?;
+
+    let predicate = values
+        .into_iter()
+        .map(|value| {
+            Predicate::Condition(Condition {
+                field: field.to_owned(),
+                operator: Operator::Eq,
+                value,
+            })
+        })
+        .reduce(|left, right| Predicate::Or(Box::new(left), Box::new(right)))
+        .expect("separated_list1 returns at least one value");
+    match negated {
+        Some(_) => Ok((unparsed, Predicate::Not(Box::new(predicate)))),
+        None => Ok((unparsed, predicate)),
+    }
+}
+
+// A quoted string, or a number kept as written
+fn value_parser(input: &str) -> IResult<&str, String> {
+    alt((string, map(number, str::to_owned)))(input)
+}
+
+fn condition_parser(input: &str) -> IResult<&str, Condition> {
+    let (unparsed, (field, _, (operator, value))) = tuple((
+        identifier,
         multispace0,
         alt((
-            delimited(tag("'"), recognize(pair(alpha1, alphanumeric0)), tag("'")),
-            digit1,
+            separated_pair(alt((tag("=~"), tag("!~"))), multispace0, regex_parser),
+            separated_pair(
+                alt((
+                    tag("="),
+                    tag(">="),
+                    tag("<="),
+                    tag("!="),
+                    tag(">"),
+                    tag("<"),
+                )),
+                multispace0,
+                value_parser,
+            ),
         )),
     ))(input)?;
 
@@ -114,18 +747,46 @@ fn condition_parser(input: &str) -> IResult<&str, Condition> {
         "<=" => Operator::Le,
         "<" => Operator::Lt,
         "!=" => Operator::Ne,
+        "=~" => Operator::Match,
+        "!~" => Operator::NotMatch,
         &_ => Operator::Eq, // This cannot happen, as it wouldn't match above
     };
 
     let cond = Condition {
         field: field.to_owned(),
         operator: out_operator,
-        value: value.to_owned(),
+        value,
     };
 
     Ok((unparsed, cond))
 }
 
+// A regular expression between slashes, where \/ stands for a slash. Other
+// escapes are left to the regular expression.
+fn regex_parser(input: &str) -> IResult<&str, String> {
+    let (rest, _) = tag("/")(input)?;
+    let mut pattern = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' => return Ok((&rest[i + 1..], pattern)),
+            '\\' => match chars.next() {
+                Some((_, '/')) => pattern.push('/'),
+                Some((_, escaped)) => {
+                    pattern.push('\\');
+                    pattern.push(escaped);
+                }
+                None => break,
+            },
+            c => pattern.push(c),
+        }
+    }
+    Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+        input,
+        nom::error::ErrorKind::Escaped,
+    )))
+}
+
 #[test]
 fn test_basic() {
     assert_eq!(
@@ -133,9 +794,10 @@ fn test_basic() {
         Ok((
             "",
             Select {
-                table: "y".to_owned(),
-                fields: vec!("x".to_owned()),
-                conditions: vec![]
+                table: Table::Name("y".to_owned()),
+                fields: vec![Expr::Field("x".to_owned()).into()],
+                conditions: vec![],
+                ..Default::default()
             }
         ))
     );
@@ -144,9 +806,14 @@ fn test_basic() {
         Ok((
             "",
             Select {
-                table: "a".to_owned(),
-                fields: vec!("x".to_owned(), "y".to_owned(), "z".to_owned()),
-                conditions: vec![]
+                table: Table::Name("a".to_owned()),
+                fields: vec![
+                    Expr::Field("x".to_owned()).into(),
+                    Expr::Field("y".to_owned()).into(),
+                    Expr::Field("z".to_owned()).into()
+                ],
+                conditions: vec![],
+                ..Default::default()
             }
         ))
     );
@@ -159,13 +826,14 @@ fn test_with_where() {
         Ok((
             "",
             Select {
-                table: "y".to_owned(),
-                fields: vec!["x".to_owned()],
-                conditions: vec![Condition {
+                table: Table::Name("y".to_owned()),
+                fields: vec![Expr::Field("x".to_owned()).into()],
+                conditions: vec![Predicate::Condition(Condition {
                     field: "x".to_owned(),
                     value: "y".to_owned(),
                     operator: Operator::Eq
-                }]
+                })],
+                ..Default::default()
             }
         ))
     );
@@ -174,13 +842,14 @@ fn test_with_where() {
         Ok((
             "",
             Select {
-                table: "y".to_owned(),
-                fields: vec!["x".to_owned()],
-                conditions: vec![Condition {
+                table: Table::Name("y".to_owned()),
+                fields: vec![Expr::Field("x".to_owned()).into()],
+                conditions: vec![Predicate::Condition(Condition {
                     field: "x".to_owned(),
                     value: "10".to_owned(),
                     operator: Operator::Eq
-                }]
+                })],
+                ..Default::default()
             }
         ))
     );
@@ -189,20 +858,21 @@ fn test_with_where() {
         Ok((
             "",
             Select {
-                table: "y".to_owned(),
-                fields: vec!["x".to_owned()],
+                table: Table::Name("y".to_owned()),
+                fields: vec![Expr::Field("x".to_owned()).into()],
                 conditions: vec![
-                    Condition {
+                    Predicate::Condition(Condition {
                         field: "x".to_owned(),
                         value: "10".to_owned(),
                         operator: Operator::Gt
-                    },
-                    Condition {
+                    }),
+                    Predicate::Condition(Condition {
                         field: "x".to_owned(),
                         value: "20".to_owned(),
                         operator: Operator::Le
-                    },
-                ]
+                    }),
+                ],
+                ..Default::default()
             }
         ))
     );
@@ -210,20 +880,427 @@ fn test_with_where() {
 
 #[test]
 fn test_fields() {
-    assert_eq!(field_parser("xxx, yyy"), Ok(("", vec!("xxx", "yyy"))));
-    assert_eq!(field_parser("aaa,bbb"), Ok(("", vec!("aaa", "bbb"))));
+    let field = |name: &str| SelectField::from(Expr::Field(name.to_owned()));
+    assert_eq!(field_parser("xxx, yyy"), Ok(("", vec![field("xxx"), field("yyy")])));
+    assert_eq!(field_parser("aaa,bbb"), Ok(("", vec![field("aaa"), field("bbb")])));
     assert_eq!(
         field_parser("a1a, b0b,c2c"),
-        Ok(("", vec!("a1a", "b0b", "c2c")))
+        Ok(("", vec![field("a1a"), field("b0b"), field("c2c")]))
+    );
+}
+
+#[test]
+fn test_calls() {
+    let call = |function: &str, field: &str| Expr::Call {
+        function: function.to_owned(),
+        args: vec![Expr::Field(field.to_owned())],
+    };
+    assert_eq!(
+        field_parser("avg(cpu), MAX( cpu ) from m"),
+        Ok((" from m", vec![call("avg", "cpu").into(), call("max", "cpu").into()]))
+    );
+    assert_eq!(call("count", "mem").to_string(), "count(mem)");
+    let (_, fields) = field_parser("derivative(cpu, 60s), difference(cpu)").unwrap();
+    assert_eq!(
+        fields[0].expr,
+        Expr::Call {
+            function: "derivative".to_owned(),
+            args: vec![Expr::Field("cpu".to_owned()), Expr::Duration(60)],
+        }
+    );
+    assert_eq!(fields[0].expr.to_string(), "derivative(cpu, 1m)");
+    assert_eq!(Expr::Duration(90).to_string(), "90s");
+    let (_, fields) = field_parser("percentile(latency, 99.9), histogram(latency, 10, 1e3)").unwrap();
+    assert_eq!(fields[0].expr.to_string(), "percentile(latency, 99.9)");
+    assert_eq!(fields[1].expr.to_string(), "histogram(latency, 10, 1000)");
+    assert!(select_parser("select avg(cpu from m").is_err());
+}
+
+#[test]
+fn test_arithmetic() {
+    let field = |name: &str| Box::new(Expr::Field(name.to_owned()));
+    let (_, fields) = field_parser("bytes_in * 8 / 1e6 AS mbps, used / total * 100").unwrap();
+    assert_eq!(
+        fields[0],
+        SelectField {
+            expr: Expr::Binary {
+                operator: BinaryOperator::Divide,
+                left: Box::new(Expr::Binary {
+                    operator: BinaryOperator::Multiply,
+                    left: field("bytes_in"),
+                    right: Box::new(Expr::Number(8.0)),
+                }),
+                right: Box::new(Expr::Number(1e6)),
+            },
+            alias: Some("mbps".to_owned()),
+        }
+    );
+    assert_eq!(fields[1].expr.to_string(), "used / total * 100");
+    assert_eq!(fields[1].alias, None);
+
+    let expr = |sql| expr_parser(sql).unwrap().1;
+    assert_eq!(
+        expr("-a%b"),
+        Expr::Binary {
+            operator: BinaryOperator::Modulo,
+            left: Box::new(Expr::Negate(field("a"))),
+            right: field("b"),
+        }
+    );
+    assert_eq!(expr("a - -2"), expr("a-(-2)"));
+    assert_eq!(expr("(a + b) * max(c) - 1").to_string(), "(a + b) * max(c) - 1");
+    assert_eq!(expr("a - (b - c)").to_string(), "a - (b - c)");
+    assert_eq!(expr("-(a + 1)").to_string(), "-(a + 1)");
+    assert_eq!(expr("a * 2 from m"), expr("a*2"));
+    assert_eq!(
+        select_parser("select cpu as load from m").unwrap().1.fields[0].alias,
+        Some("load".to_owned())
     );
 }
 
 #[test]
 fn test_table() {
-    assert_eq!(table_parser(" from table1"), Ok(("", "table1")));
-    assert_eq!(table_parser(" FROM table2"), Ok(("", "table2")));
+    let name = |name: &str| Table::Name(name.to_owned());
+    assert_eq!(table_parser(" from table1"), Ok(("", name("table1"))));
+    assert_eq!(table_parser(" FROM table2"), Ok(("", name("table2"))));
     assert_eq!(
         table_parser(" from table1 where XX"),
-        Ok((" where XX", "table1"))
+        Ok((" where XX", name("table1")))
+    );
+}
+
+#[test]
+fn test_subquery() {
+    let (unparsed, select) = select_parser(
+        "select max(avg_cpu) from ( select avg(cpu) as avg_cpu from m group by time(1m), host ) group by time(1h)",
+    )
+    .unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!(select.group_by.time.unwrap().interval, 3600);
+    let inner = match &select.table {
+        Table::Subquery(inner) => inner,
+        other => panic!("expected a subquery, found {:?}", other),
+    };
+    assert_eq!(inner.fields[0].alias, Some("avg_cpu".to_owned()));
+    assert_eq!(inner.group_by.tags, TagGrouping::Keys(vec!["host".to_owned()]));
+    assert_eq!(select.table.name(), "m");
+    assert!(select_parser("select x from (select y from m").is_err());
+}
+
+#[test]
+fn test_literals() {
+    let condition = |field: &str, value: &str| {
+        Predicate::Condition(Condition {
+            field: field.to_owned(),
+            operator: Operator::Eq,
+            value: value.to_owned(),
+        })
+    };
+    assert_eq!(
+        select_parser("select mean(\"cpu.load\") from `web servers` where host_name = 'eu-west-1' and dc = \"it's\""),
+        Ok((
+            "",
+            Select {
+                table: Table::Name("web servers".to_owned()),
+                fields: vec![Expr::Call {
+                    function: "mean".to_owned(),
+                    args: vec![Expr::Field("cpu.load".to_owned())],
+                }
+                .into()],
+                conditions: vec![condition("host_name", "eu-west-1"), condition("dc", "it's")],
+                ..Default::default()
+            }
+        ))
+    );
+    assert_eq!(
+        where_parser("where value > -1.5e3 and rack = 2.5"),
+        Ok((
+            "",
+            vec![
+                Predicate::Value {
+                    operator: Operator::Gt,
+                    value: -1500.0
+                },
+                condition("rack", "2.5"),
+            ]
+        ))
+    );
+    assert_eq!(where_parser("where dc in ('', 'a b')").map(|(_, p)| p.len()), Ok(1));
+    assert_eq!(fill_parser("fill(-0.5)"), Ok(("", Fill::Value(-0.5))));
+    assert!(where_parser("where value between 'inf' and 1").is_err());
+}
+
+#[test]
+fn test_negative_time() {
+    assert_eq!(
+        where_parser("where time >= -2208988800"),
+        Ok((
+            "",
+            vec![Predicate::Time {
+                operator: Operator::Ge,
+                time: TimeExpr::Absolute(-2208988800 * NANOS_IN_SECOND)
+            }]
+        ))
+    );
+}
+
+#[test]
+fn test_group_by_time() {
+    assert_eq!(duration_parser("90s"), Ok(("", 90)));
+    assert_eq!(duration_parser("5m"), Ok(("", 300)));
+    assert_eq!(duration_parser("2w"), Ok(("", 1209600)));
+    assert!(duration_parser("5x").is_err());
+    assert_eq!(
+        select_parser("select avg(x) from y where time > 0 group by time(5m)"),
+        Ok((
+            "",
+            Select {
+                table: Table::Name("y".to_owned()),
+                fields: vec![Expr::Call {
+                    function: "avg".to_owned(),
+                    args: vec![Expr::Field("x".to_owned())]
+                }
+                .into()],
+                conditions: vec![Predicate::Time {
+                    operator: Operator::Gt,
+                    time: TimeExpr::Absolute(0)
+                }],
+                group_by: GroupBy {
+                    time: Some(TimeWindow {
+                        interval: 300,
+                        offset: 0
+                    }),
+                    tags: TagGrouping::None
+                },
+                ..Default::default()
+            }
+        ))
+    );
+    assert_eq!(
+        group_by_parser("GROUP BY time(1h, 15m)"),
+        Ok((
+            "",
+            GroupBy {
+                time: Some(TimeWindow {
+                    interval: 3600,
+                    offset: 900
+                }),
+                tags: TagGrouping::None
+            }
+        ))
+    );
+    assert!(group_by_parser("group by time(0s)").is_err());
+}
+
+#[test]
+fn test_group_by_tags() {
+    assert_eq!(
+        group_by_parser("group by host, time(1m), dc"),
+        Ok((
+            "",
+            GroupBy {
+                time: Some(TimeWindow {
+                    interval: 60,
+                    offset: 0
+                }),
+                tags: TagGrouping::Keys(vec!["host".to_owned(), "dc".to_owned()])
+            }
+        ))
+    );
+    assert_eq!(
+        group_by_parser("group by *"),
+        Ok((
+            "",
+            GroupBy {
+                time: None,
+                tags: TagGrouping::All
+            }
+        ))
+    );
+    assert!(group_by_parser("group by time(1m), time(5m)").is_err());
+}
+
+#[test]
+fn test_fill() {
+    let (_, select) = select_parser("select max(cpu) from m group by time(1m) fill(previous)").unwrap();
+    assert_eq!(select.fill, Fill::Previous);
+    assert_eq!(fill_parser("FILL(null)"), Ok(("", Fill::Null)));
+    assert_eq!(fill_parser("fill(none)"), Ok(("", Fill::None)));
+    assert_eq!(fill_parser("fill( linear )"), Ok(("", Fill::Linear)));
+    assert_eq!(fill_parser("fill(0)"), Ok(("", Fill::Value(0.0))));
+    assert_eq!(fill_parser("fill(-1.5)"), Ok(("", Fill::Value(-1.5))));
+    assert!(fill_parser("fill(nan)").is_err());
+    assert!(fill_parser("fill(1e400)").is_err());
+    assert!(fill_parser("fill(foo)").is_err());
+}
+
+#[test]
+fn test_order_and_limits() {
+    let (unparsed, select) =
+        select_parser("select cpu from m order by time desc limit 10 offset 5 slimit 2 soffset 1").unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!(select.order, Order::Desc);
+    assert_eq!(select.limit, Some(10));
+    assert_eq!(select.offset, 5);
+    assert_eq!(select.slimit, Some(2));
+    assert_eq!(select.soffset, 1);
+
+    let (_, select) = select_parser("select cpu from m ORDER BY time LIMIT 1").unwrap();
+    assert_eq!(select.order, Order::Asc);
+    assert_eq!(select.limit, Some(1));
+    assert_eq!(select.slimit, None);
+
+    let (unparsed, _) = select_parser("select cpu from m order by cpu").unwrap();
+    assert_eq!(unparsed, " order by cpu");
+}
+
+#[test]
+fn test_shift() {
+    let (unparsed, select) = select_parser("select cpu from m order by time desc offset 7d limit 10 offset 5").unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!(select.shift, 7 * 86400);
+    assert_eq!(select.offset, 5);
+
+    let (_, select) = select_parser("select cpu from m offset 5").unwrap();
+    assert_eq!(select.shift, 0);
+    assert_eq!(select.offset, 5);
+
+    let (unparsed, select) = select_parser("select cpu from m limit 10 offset 7d").unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!((select.limit, select.offset, select.shift), (Some(10), 0, 7 * 86400));
+    let (unparsed, select) = select_parser("select cpu from m limit 10 offset 1h offset 5 slimit 2").unwrap();
+    assert_eq!(unparsed, "");
+    assert_eq!((select.offset, select.shift, select.slimit), (5, 3600, Some(2)));
+    // Not whole seconds, and not a count either
+    let (unparsed, select) = select_parser("select cpu from m limit 10 offset 5ms").unwrap();
+    assert_eq!(unparsed, " offset 5ms");
+    assert_eq!((select.offset, select.shift), (0, 0));
+    assert!(select_parser("select cpu from m offset 1d limit 1 offset 2d").is_err());
+
+    let (_, select) = select_parser("select max(cpu) - time_shift(max(cpu), 1w) from m").unwrap();
+    assert_eq!(select.fields[0].expr.to_string(), "max(cpu) - time_shift(max(cpu), 1w)");
+}
+
+#[test]
+fn test_boolean_where() {
+    let eq = |field: &str, value: &str| {
+        Predicate::Condition(Condition {
+            field: field.to_owned(),
+            operator: Operator::Eq,
+            value: value.to_owned(),
+        })
+    };
+    let or = |left, right| Predicate::Or(Box::new(left), Box::new(right));
+    let not = |predicate| Predicate::Not(Box::new(predicate));
+
+    assert_eq!(
+        where_parser("where host = 'a' or host = 'b' and dc = 'x'"),
+        Ok((
+            "",
+            vec![or(
+                eq("host", "a"),
+                Predicate::And(Box::new(eq("host", "b")), Box::new(eq("dc", "x")))
+            )]
+        ))
+    );
+    assert_eq!(
+        where_parser("where (host = 'a' OR host = 'b') AND NOT dc = 'x' group by host"),
+        Ok((" group by host", vec![or(eq("host", "a"), eq("host", "b")), not(eq("dc", "x"))]))
+    );
+    assert_eq!(
+        where_parser("where host in ('a', 'b', 'c')"),
+        Ok(("", vec![or(or(eq("host", "a"), eq("host", "b")), eq("host", "c"))]))
+    );
+    assert_eq!(
+        where_parser("where not(host not in ('a'))"),
+        Ok(("", vec![not(not(eq("host", "a")))]))
+    );
+    assert_eq!(
+        where_parser("where notify = 'x'"),
+        Ok(("", vec![eq("notify", "x")]))
+    );
+    assert!(where_parser("where (host = 'a'").is_err());
+}
+
+#[test]
+fn test_regex() {
+    assert_eq!(regex_parser("/^web-\\d+$/ and"), Ok((" and", "^web-\\d+$".to_owned())));
+    assert_eq!(regex_parser("/a\\/b/"), Ok(("", "a/b".to_owned())));
+    assert!(regex_parser("/abc").is_err());
+    assert_eq!(
+        condition_parser("host =~ /^web-\\d+$/"),
+        Ok((
+            "",
+            Condition {
+                field: "host".to_owned(),
+                operator: Operator::Match,
+                value: "^web-\\d+$".to_owned()
+            }
+        ))
+    );
+    assert_eq!(condition_parser("host!~/a/").unwrap().1.operator, Operator::NotMatch);
+    assert!(condition_parser("host = /a/").is_err());
+
+    let (_, fields) = field_parser("/^cpu_.*/, max(/a\\/b/)").unwrap();
+    assert_eq!(fields[0].expr, Expr::Regex("^cpu_.*".to_owned()));
+    assert_eq!(fields[1].expr.to_string(), "max(/a\\/b/)");
+}
+
+#[test]
+fn test_value_conditions() {
+    let value = |operator, value| Predicate::Value { operator, value };
+    assert_eq!(
+        where_parser("where value > 90 and host = 'a'"),
+        Ok((
+            "",
+            vec![
+                value(Operator::Gt, 90.0),
+                Predicate::Condition(Condition {
+                    field: "host".to_owned(),
+                    operator: Operator::Eq,
+                    value: "a".to_owned()
+                })
+            ]
+        ))
+    );
+    assert_eq!(
+        where_parser("where value between -1.5 and 20 or value = 0"),
+        Ok((
+            "",
+            vec![Predicate::Or(
+                Box::new(Predicate::And(
+                    Box::new(value(Operator::Ge, -1.5)),
+                    Box::new(value(Operator::Le, 20.0))
+                )),
+                Box::new(value(Operator::Eq, 0.0))
+            )]
+        ))
+    );
+
+    assert!(where_parser("where value between 'a' and 1").is_err());
+    assert!(where_parser("where value = 'x'").is_err());
+    assert!(where_parser("where value != host").is_err());
+    assert!(select_parser("select cpu from m where value >= 'x'").is_err());
+}
+
+#[test]
+fn test_time_conditions() {
+    let time = |operator, time| Predicate::Time { operator, time };
+    assert_eq!(
+        where_parser("where time > now() - 1h and host = 'a'").unwrap().1[0],
+        time(Operator::Gt, TimeExpr::Now(-3600 * NANOS_IN_SECOND))
+    );
+    // A range is split into two conditions at the top level
+    assert_eq!(
+        where_parser("where time between '2026-10-17' and '2026-10-17T00:00:01Z' + 500ms"),
+        Ok((
+            "",
+            vec![
+                time(Operator::Ge, TimeExpr::Absolute(1792195200 * NANOS_IN_SECOND)),
+                time(Operator::Le, TimeExpr::Absolute(1792195201500000000))
+            ]
+        ))
     );
+    assert_eq!(duration_parser("2000ms"), Ok(("", 2)));
+    assert!(duration_parser("1500ms").is_err());
 }
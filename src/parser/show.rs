@@ -0,0 +1,192 @@
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case};
+use nom::character::complete::{digit1, multispace0, multispace1};
+use nom::combinator::{map, map_res, opt};
+use nom::sequence::{preceded, tuple};
+use nom::IResult;
+
+use super::lexer::identifier;
+use super::select::{where_parser, Predicate};
+
+#[derive(Debug, PartialEq)]
+pub enum Show {
+    Cardinality {
+        limit: Option<usize>,
+    },
+    // The metrics with a series matching the conditions
+    Metrics {
+        conditions: Vec<Predicate>,
+        limit: Option<usize>,
+    },
+    // Tag keys of the matching series, of `metric` or of every metric
+    TagKeys {
+        metric: Option<String>,
+        conditions: Vec<Predicate>,
+        limit: Option<usize>,
+    },
+    TagValues {
+        metric: Option<String>,
+        key: String,
+        conditions: Vec<Predicate>,
+        limit: Option<usize>,
+    },
+    Series {
+        metric: Option<String>,
+        conditions: Vec<Predicate>,
+        limit: Option<usize>,
+    },
+}
+
+pub fn show_parser(input: &str) -> IResult<&str, Show> {
+    preceded(
+        tuple((tag_no_case("show"), multispace1)),
+        alt((
+            map(
+                preceded(tag_no_case("cardinality"), opt(limit_parser)),
+                |limit| Show::Cardinality { limit },
+            ),
+            map(
+                preceded(tag_no_case("metrics"), tuple((conditions_parser, opt(limit_parser)))),
+                |(conditions, limit)| Show::Metrics { conditions, limit },
+            ),
+            map(
+                preceded(
+                    tuple((tag_no_case("tag"), multispace1, tag_no_case("keys"))),
+                    tuple((opt(from_parser), conditions_parser, opt(limit_parser))),
+                ),
+                |(metric, conditions, limit)| Show::TagKeys {
+                    metric,
+                    conditions,
+                    limit,
+                },
+            ),
+            map(
+                preceded(
+                    tuple((tag_no_case("tag"), multispace1, tag_no_case("values"))),
+                    tuple((opt(from_parser), with_key_parser, conditions_parser, opt(limit_parser))),
+                ),
+                |(metric, key, conditions, limit)| Show::TagValues {
+                    metric,
+                    key,
+                    conditions,
+                    limit,
+                },
+            ),
+            map(
+                preceded(
+                    tag_no_case("series"),
+                    tuple((opt(from_parser), conditions_parser, opt(limit_parser))),
+                ),
+                |(metric, conditions, limit)| Show::Series {
+                    metric,
+                    conditions,
+                    limit,
+                },
+            ),
+        )),
+    )(input)
+}
+
+fn limit_parser(input: &str) -> IResult<&str, usize> {
+    preceded(
+        tuple((multispace1, tag_no_case("limit"), multispace1)),
+        map_res(digit1, |digits: &str| digits.parse()),
+    )(input)
+}
+
+fn from_parser(input: &str) -> IResult<&str, String> {
+    map(
+        preceded(tuple((multispace1, tag_no_case("from"), multispace1)), identifier),
+        str::to_owned,
+    )(input)
+}
+
+// WITH KEY = host
+fn with_key_parser(input: &str) -> IResult<&str, String> {
+    map(
+        preceded(
+            tuple((
+                multispace1,
+                tag_no_case("with"),
+                multispace1,
+                tag_no_case("key"),
+                multispace0,
+                tag("="),
+                multispace0,
+            )),
+            identifier,
+        ),
+        str::to_owned,
+    )(input)
+}
+
+fn conditions_parser(input: &str) -> IResult<&str, Vec<Predicate>> {
+    map(opt(preceded(multispace1, where_parser)), Option::unwrap_or_default)(input)
+}
+
+#[test]
+fn test_cardinality() {
+    assert_eq!(
+        show_parser("show cardinality"),
+        Ok(("", Show::Cardinality { limit: None }))
+    );
+    assert_eq!(
+        show_parser("SHOW CARDINALITY LIMIT 5"),
+        Ok(("", Show::Cardinality { limit: Some(5) }))
+    );
+}
+
+#[test]
+fn test_schema() {
+    use super::select::{Condition, Operator};
+    let host_a = || {
+        vec![Predicate::Condition(Condition {
+            field: "host".to_owned(),
+            operator: Operator::Eq,
+            value: "a".to_owned(),
+        })]
+    };
+    assert_eq!(
+        show_parser("show metrics"),
+        Ok(("", Show::Metrics { conditions: vec![], limit: None }))
+    );
+    assert_eq!(
+        show_parser("SHOW METRICS WHERE host = 'a' LIMIT 3"),
+        Ok(("", Show::Metrics { conditions: host_a(), limit: Some(3) }))
+    );
+    assert_eq!(
+        show_parser("show tag keys from cpu"),
+        Ok((
+            "",
+            Show::TagKeys {
+                metric: Some("cpu".to_owned()),
+                conditions: vec![],
+                limit: None
+            }
+        ))
+    );
+    assert_eq!(
+        show_parser("show tag values with key = dc where host = 'a' limit 10"),
+        Ok((
+            "",
+            Show::TagValues {
+                metric: None,
+                key: "dc".to_owned(),
+                conditions: host_a(),
+                limit: Some(10)
+            }
+        ))
+    );
+    assert_eq!(
+        show_parser("show series from \"cpu.load\" where host = 'a'"),
+        Ok((
+            "",
+            Show::Series {
+                metric: Some("cpu.load".to_owned()),
+                conditions: host_a(),
+                limit: None
+            }
+        ))
+    );
+    assert!(show_parser("show tag values from cpu").is_err());
+}
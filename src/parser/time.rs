@@ -0,0 +1,196 @@
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case};
+use nom::character::complete::{digit1, multispace0};
+use nom::combinator::{map, map_opt, opt, recognize};
+use nom::multi::many0;
+use nom::sequence::{delimited, pair, tuple};
+use nom::IResult;
+
+use super::lexer::string;
+
+pub const NANOS_IN_SECOND: i128 = 1_000_000_000;
+
+/// A point in time as written in a query, in nanoseconds since the epoch.
+/// Relative times are resolved by the planner, so that every `now()` of a
+/// query is the same instant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeExpr {
+    Absolute(i128),
+    // now() moved by this many nanoseconds
+    Now(i128),
+}
+
+impl TimeExpr {
+    pub fn resolve(&self, now: i128) -> i128 {
+        match self {
+            TimeExpr::Absolute(time) => *time,
+            TimeExpr::Now(offset) => now.saturating_add(*offset),
+        }
+    }
+}
+
+// now(), a quoted RFC 3339 time or seconds since the epoch, followed by any
+// number of `+ duration` or `- duration`
+pub fn time_expr_parser(input: &str) -> IResult<&str, TimeExpr> {
+    let (unparsed, (base, shifts)) = pair(
+        alt((
+            map(tuple((tag_no_case("now"), multispace0, tag("("), multispace0, tag(")"))), |_| {
+                TimeExpr::Now(0)
+            }),
+            map_opt(string, |text| parse_rfc3339(&text).map(TimeExpr::Absolute)),
+            map_opt(recognize(pair(opt(tag("-")), digit1)), |seconds: &str| {
+                Some(TimeExpr::Absolute(seconds.parse::<i128>().ok()?.checked_mul(NANOS_IN_SECOND)?))
+            }),
+        )),
+        many0(pair(
+            delimited(multispace0, alt((tag("+"), tag("-"))), multispace0),
+            duration_nanos_parser,
+        )),
+    )(input)?;
+
+    let shifted = shifts.into_iter().try_fold(base, |time, (sign, duration)| {
+        let duration = if sign == "-" { -duration } else { duration };
+        Some(match time {
+            TimeExpr::Absolute(time) => TimeExpr::Absolute(time.checked_add(duration)?),
+            TimeExpr::Now(offset) => TimeExpr::Now(offset.checked_add(duration)?),
+        })
+    });
+    match shifted {
+        Some(shifted) => Ok((unparsed, shifted)),
+        None => Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+            input,
+            nom::error::ErrorKind::TooLarge,
+        ))),
+    }
+}
+
+// A count of ns, us, ms, s, m, h, d or w, in nanoseconds
+pub fn duration_nanos_parser(input: &str) -> IResult<&str, i128> {
+    map_opt(
+        pair(
+            digit1,
+            alt((
+                map(tag("ns"), |_| 1),
+                map(alt((tag("us"), tag("µs"))), |_| 1_000),
+                map(tag("ms"), |_| 1_000_000),
+                map(tag("s"), |_| NANOS_IN_SECOND),
+                map(tag("m"), |_| 60 * NANOS_IN_SECOND),
+                map(tag("h"), |_| 3600 * NANOS_IN_SECOND),
+                map(tag("d"), |_| 86400 * NANOS_IN_SECOND),
+                map(tag("w"), |_| 604800 * NANOS_IN_SECOND),
+            )),
+        ),
+        |(count, unit): (&str, i128)| count.parse::<i128>().ok()?.checked_mul(unit),
+    )(input)
+}
+
+// YYYY-MM-DD, optionally followed by T or a space, HH:MM:SS with a fraction
+// and an offset of Z or +HH:MM. Without an offset the time is in UTC.
+pub fn parse_rfc3339(text: &str) -> Option<i128> {
+    let number = |part: &str| -> Option<i128> {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let (year, rest) = (text.get(0..4)?, text.get(4..)?);
+    let (month, day) = (rest.strip_prefix('-')?.get(0..2)?, rest.get(4..6)?);
+    if rest.get(3..4)? != "-" {
+        return None;
+    }
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month[month as usize - 1] {
+        return None;
+    }
+    let mut nanos = days_from_civil(year, month, day) * 86400 * NANOS_IN_SECOND;
+
+    let rest = &rest[6..];
+    if rest.is_empty() {
+        return Some(nanos);
+    }
+    let rest = rest.strip_prefix('T').or_else(|| rest.strip_prefix('t')).or_else(|| rest.strip_prefix(' '))?;
+    let (hour, minute, second) = (number(rest.get(0..2)?)?, number(rest.get(3..5)?)?, number(rest.get(6..8)?)?);
+    if rest.get(2..3)? != ":" || rest.get(5..6)? != ":" || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    nanos += (hour * 3600 + minute * 60 + second) * NANOS_IN_SECOND;
+
+    let mut rest = &rest[8..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 || digits > 9 {
+            return None;
+        }
+        nanos += number(&fraction[..digits])? * 10i128.pow(9 - digits as u32);
+        rest = &fraction[digits..];
+    }
+    match rest {
+        "" | "Z" | "z" => Some(nanos),
+        _ => {
+            let sign = match rest.get(0..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.get(3..4)? != ":" {
+                return None;
+            }
+            let (hours, minutes) = (number(rest.get(1..3)?)?, number(rest.get(4..6)?)?);
+            Some(nanos - sign * (hours * 3600 + minutes * 60) * NANOS_IN_SECOND)
+        }
+    }
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i128, month: i128, day: i128) -> i128 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[test]
+fn test_rfc3339() {
+    let seconds = |text| parse_rfc3339(text).map(|nanos| nanos / NANOS_IN_SECOND);
+    assert_eq!(seconds("1970-01-01T00:00:00Z"), Some(0));
+    assert_eq!(seconds("2026-10-17T12:00:00Z"), Some(1792238400));
+    assert_eq!(seconds("2026-10-17"), Some(1792195200));
+    assert_eq!(seconds("2026-10-17 14:00:00+02:00"), Some(1792238400));
+    assert_eq!(seconds("1900-01-01T00:00:00Z"), Some(-2208988800));
+    assert_eq!(seconds("2024-02-29T00:00:00Z"), Some(1709164800));
+    assert_eq!(parse_rfc3339("1970-01-01T00:00:01.5Z"), Some(1_500_000_000));
+    assert_eq!(parse_rfc3339("2023-02-29"), None);
+    assert_eq!(parse_rfc3339("2026-10-17T25:00:00Z"), None);
+    assert_eq!(parse_rfc3339("2026-10-17T12:00:00+0200"), None);
+    assert_eq!(parse_rfc3339("yesterday"), None);
+}
+
+#[test]
+fn test_time_expr() {
+    assert_eq!(duration_nanos_parser("15ms"), Ok(("", 15_000_000)));
+    assert_eq!(duration_nanos_parser("2m"), Ok(("", 120 * NANOS_IN_SECOND)));
+    assert_eq!(time_expr_parser("now()"), Ok(("", TimeExpr::Now(0))));
+    assert_eq!(
+        time_expr_parser("now() - 1h + 30s and"),
+        Ok((" and", TimeExpr::Now(-3570 * NANOS_IN_SECOND)))
+    );
+    assert_eq!(
+        time_expr_parser("'1970-01-02T00:00:00Z' - 1d"),
+        Ok(("", TimeExpr::Absolute(0)))
+    );
+    assert_eq!(
+        time_expr_parser("-60 + 500ms"),
+        Ok(("", TimeExpr::Absolute(-59_500_000_000)))
+    );
+    assert_eq!(TimeExpr::Now(-5).resolve(10), 5);
+    assert!(time_expr_parser("'tomorrow'").is_err());
+    let huge = "200000000000000000000000w";
+    assert!(time_expr_parser(&format!("now() + {}", huge)).is_ok());
+    assert!(time_expr_parser(&format!("now() + {} + {}", huge, huge)).is_err());
+    assert!(time_expr_parser(&format!("0 - {} - {}", huge, huge)).is_err());
+    assert_eq!(TimeExpr::Now(i128::MAX).resolve(10), i128::MAX);
+}
@@ -0,0 +1,420 @@
+use anyhow::{anyhow, bail, Result};
+
+use super::sketch::Sketch;
+use crate::db::Timestamp;
+use crate::parser::select::Expr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+    First,
+    Last,
+    // Per second increase of a counter over the whole window, and between
+    // its last two points
+    Rate,
+    Irate,
+    // The value at a percentile by nearest rank, from every value
+    Percentile(f64),
+    // The middle value, or the mean of the two middle ones
+    Median,
+    // A percentile estimated from a sketch, so values aren't kept
+    ApproxPercentile(f64),
+    // Sample standard deviation
+    Stddev,
+    // Difference between the largest and smallest values
+    Spread,
+    // How many values are at most a bound, a bucket of a histogram
+    AtMost(f64),
+}
+
+impl Aggregate {
+    pub fn is_aggregate(function: &str) -> bool {
+        matches!(
+            function,
+            "count"
+                | "sum"
+                | "avg"
+                | "mean"
+                | "min"
+                | "max"
+                | "first"
+                | "last"
+                | "rate"
+                | "irate"
+                | "percentile"
+                | "median"
+                | "approx_percentile"
+                | "approx_median"
+                | "stddev"
+                | "spread"
+                | "histogram"
+        )
+    }
+
+    // The aggregates a call computes, one per column, and the argument they
+    // read. histogram(x, 10, 100) counts values up to 10, up to 100 and all.
+    pub fn from_call<'a>(function: &str, args: &'a [Expr]) -> Result<(Vec<Aggregate>, &'a Expr)> {
+        let (arg, rest) = args
+            .split_first()
+            .ok_or_else(|| anyhow!("{} needs an argument", function))?;
+        let numbers = rest
+            .iter()
+            .map(|arg| match arg {
+                Expr::Number(number) => Ok(*number),
+                _ => bail!("{} takes a metric and numbers, found {}", function, arg),
+            })
+            .collect::<Result<Vec<f64>>>()?;
+        let single = |aggregate| {
+            if !numbers.is_empty() {
+                bail!("{} takes a single metric", function);
+            }
+            Ok(vec![aggregate])
+        };
+        let percentile = || match numbers.as_slice() {
+            [percentile] if (0.0..=100.0).contains(percentile) => Ok(*percentile),
+            _ => bail!("{} takes a metric and a percentile from 0 to 100", function),
+        };
+
+        let aggregates = match function {
+            "count" => single(Aggregate::Count)?,
+            "sum" => single(Aggregate::Sum)?,
+            "avg" | "mean" => single(Aggregate::Avg)?,
+            "min" => single(Aggregate::Min)?,
+            "max" => single(Aggregate::Max)?,
+            "first" => single(Aggregate::First)?,
+            "last" => single(Aggregate::Last)?,
+            "rate" => single(Aggregate::Rate)?,
+            "irate" => single(Aggregate::Irate)?,
+            "percentile" => vec![Aggregate::Percentile(percentile()?)],
+            "median" => single(Aggregate::Median)?,
+            "approx_percentile" => vec![Aggregate::ApproxPercentile(percentile()?)],
+            "approx_median" => single(Aggregate::ApproxPercentile(50.0))?,
+            "stddev" => single(Aggregate::Stddev)?,
+            "spread" => single(Aggregate::Spread)?,
+            "histogram" => {
+                if numbers.is_empty() || numbers.windows(2).any(|pair| pair[0] >= pair[1]) {
+                    bail!("histogram takes a metric and increasing bucket bounds");
+                }
+                numbers
+                    .iter()
+                    .chain(&[f64::INFINITY])
+                    .map(|bound| Aggregate::AtMost(*bound))
+                    .collect()
+            }
+            _ => bail!("unknown function {}", function),
+        };
+        Ok((aggregates, arg))
+    }
+}
+
+/// Running state of an aggregate. Points are folded in one at a time, so
+/// nothing is buffered however many points are aggregated, except for exact
+/// percentiles and medians which need every value.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    aggregate: Aggregate,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    first: Option<(Timestamp, f64)>,
+    last: Option<(Timestamp, f64)>,
+    // Counter increase from the first to the last point, and the point
+    // before the last
+    increase: f64,
+    previous: Option<(Timestamp, f64)>,
+    // Rates of other series merged in
+    merged_rate: Option<f64>,
+    // Sum of squared differences from the mean
+    m2: f64,
+    values: Vec<f64>,
+    sketch: Sketch,
+    // Values up to the bound of AtMost
+    within: u64,
+}
+
+impl Accumulator {
+    pub fn new(aggregate: Aggregate) -> Self {
+        Accumulator {
+            aggregate,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            first: None,
+            last: None,
+            increase: 0.0,
+            previous: None,
+            merged_rate: None,
+            m2: 0.0,
+            values: Vec::new(),
+            sketch: Sketch::default(),
+            within: 0,
+        }
+    }
+
+    // Points must be pushed in time order for rates to be right
+    pub fn push(&mut self, time: Timestamp, value: f64) {
+        let delta = value - self.mean();
+        self.count += 1;
+        self.sum += value;
+        self.m2 += delta * (value - self.mean());
+        match self.aggregate {
+            Aggregate::Percentile(_) | Aggregate::Median => self.values.push(value),
+            Aggregate::ApproxPercentile(_) => self.sketch.push(value),
+            Aggregate::AtMost(bound) if value <= bound => self.within += 1,
+            _ => {}
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        match self.first {
+            Some((first, _)) if first <= time => {}
+            _ => self.first = Some((time, value)),
+        }
+        match self.last {
+            Some((last, _)) if last > time => {}
+            Some((last, last_value)) if last < time => {
+                self.increase += counter_increase(last_value, value);
+                self.previous = self.last;
+                self.last = Some((time, value));
+            }
+            _ => self.last = Some((time, value)),
+        }
+    }
+
+    // Folds in another accumulator of the same aggregate, as when several
+    // series fall into one group
+    pub fn merge(&mut self, other: &Accumulator) {
+        if let Aggregate::Rate | Aggregate::Irate = self.aggregate {
+            // The rates of counters add up, their points don't mix
+            if let Some(rate) = other.finish() {
+                self.merged_rate = Some(self.merged_rate.unwrap_or(0.0) + rate);
+            }
+            return;
+        }
+        if self.count > 0 && other.count > 0 {
+            let delta = other.mean() - self.mean();
+            let (count, other_count) = (self.count as f64, other.count as f64);
+            self.m2 += delta * delta * count * other_count / (count + other_count);
+        }
+        self.m2 += other.m2;
+        self.values.extend_from_slice(&other.values);
+        self.sketch.merge(&other.sketch);
+        self.within += other.within;
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        if let Some((time, value)) = other.first {
+            match self.first {
+                Some((first, _)) if first <= time => {}
+                _ => self.first = Some((time, value)),
+            }
+        }
+        if let Some((time, value)) = other.last {
+            match self.last {
+                Some((last, _)) if last > time => {}
+                _ => self.last = Some((time, value)),
+            }
+        }
+    }
+
+    // None when there was nothing to aggregate, except for COUNT which is 0
+    pub fn finish(&self) -> Option<f64> {
+        if let Aggregate::Rate | Aggregate::Irate = self.aggregate {
+            let own_rate = match (self.aggregate, self.first, self.previous, self.last) {
+                (Aggregate::Rate, Some((from, _)), _, Some((to, _))) if to > from => {
+                    Some(self.increase / (to - from) as f64)
+                }
+                (Aggregate::Irate, _, Some((from, from_value)), Some((to, to_value))) => {
+                    Some(counter_increase(from_value, to_value) / (to - from) as f64)
+                }
+                _ => None,
+            };
+            return match (own_rate, self.merged_rate) {
+                (Some(own), Some(merged)) => Some(own + merged),
+                (own, merged) => own.or(merged),
+            };
+        }
+
+        if self.count == 0 {
+            return match self.aggregate {
+                Aggregate::Count | Aggregate::AtMost(_) => Some(0.0),
+                _ => None,
+            };
+        }
+        match self.aggregate {
+            Aggregate::Count => Some(self.count as f64),
+            Aggregate::Sum => Some(self.sum),
+            Aggregate::Avg => Some(self.sum / self.count as f64),
+            Aggregate::Min => Some(self.min),
+            Aggregate::Max => Some(self.max),
+            Aggregate::First => self.first.map(|(_, value)| value),
+            Aggregate::Last => self.last.map(|(_, value)| value),
+            Aggregate::Rate | Aggregate::Irate => None,
+            Aggregate::Percentile(percentile) => {
+                let sorted = self.sorted_values();
+                let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+                Some(sorted[rank.clamp(1, sorted.len()) - 1])
+            }
+            Aggregate::Median => {
+                // The same value twice for an odd count
+                let sorted = self.sorted_values();
+                let count = sorted.len();
+                Some((sorted[(count - 1) / 2] + sorted[count / 2]) / 2.0)
+            }
+            Aggregate::ApproxPercentile(percentile) => self.sketch.quantile(percentile / 100.0),
+            Aggregate::Stddev if self.count < 2 => None,
+            Aggregate::Stddev => Some((self.m2 / (self.count - 1) as f64).sqrt()),
+            Aggregate::Spread => Some(self.max - self.min),
+            Aggregate::AtMost(_) => Some(self.within as f64),
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    fn sorted_values(&self) -> Vec<f64> {
+        let mut sorted = self.values.clone();
+        sorted.sort_by(f64::total_cmp);
+        sorted
+    }
+}
+
+// How much a counter went up from one value to the next. A counter that went
+// down was reset, and counted up from zero since.
+pub fn counter_increase(from: f64, to: f64) -> f64 {
+    if to < from {
+        to
+    } else {
+        to - from
+    }
+}
+
+#[test]
+fn test_accumulator() {
+    let points = [(10, 4.0), (5, 1.0), (20, 7.0)];
+    let results: Vec<_> = [
+        Aggregate::Count,
+        Aggregate::Sum,
+        Aggregate::Avg,
+        Aggregate::Min,
+        Aggregate::Max,
+        Aggregate::First,
+        Aggregate::Last,
+    ]
+    .iter()
+    .map(|aggregate| {
+        let mut acc = Accumulator::new(*aggregate);
+        for (time, value) in points {
+            acc.push(time, value);
+        }
+        acc.finish()
+    })
+    .collect();
+    assert_eq!(
+        results,
+        vec![Some(3.0), Some(12.0), Some(4.0), Some(1.0), Some(7.0), Some(1.0), Some(7.0)]
+    );
+
+    assert_eq!(Accumulator::new(Aggregate::Count).finish(), Some(0.0));
+    assert_eq!(Accumulator::new(Aggregate::Avg).finish(), None);
+}
+
+#[test]
+fn test_merge() {
+    let mut a = Accumulator::new(Aggregate::Avg);
+    a.push(10, 2.0);
+    let mut b = Accumulator::new(Aggregate::Avg);
+    b.push(5, 4.0);
+    b.push(20, 6.0);
+    a.merge(&b);
+    assert_eq!(a.finish(), Some(4.0));
+    assert_eq!(a.first, Some((5, 4.0)));
+    assert_eq!(a.last, Some((20, 6.0)));
+
+    a.merge(&Accumulator::new(Aggregate::Avg));
+    assert_eq!(a.finish(), Some(4.0));
+}
+
+#[test]
+fn test_rates() {
+    let rate = |aggregate, points: &[(Timestamp, f64)]| {
+        let mut acc = Accumulator::new(aggregate);
+        for (time, value) in points {
+            acc.push(*time, *value);
+        }
+        acc
+    };
+    // Reset between 20 and 30
+    let points = [(0, 10.0), (10, 30.0), (20, 50.0), (30, 5.0), (40, 25.0)];
+    assert_eq!(rate(Aggregate::Rate, &points).finish(), Some(1.625));
+    assert_eq!(rate(Aggregate::Irate, &points).finish(), Some(2.0));
+    assert_eq!(rate(Aggregate::Irate, &points[..4]).finish(), Some(0.5));
+    assert_eq!(rate(Aggregate::Rate, &points[..1]).finish(), None);
+    assert_eq!(rate(Aggregate::Irate, &[]).finish(), None);
+
+    let mut merged = rate(Aggregate::Rate, &points);
+    merged.merge(&rate(Aggregate::Rate, &[(0, 0.0), (10, 5.0)]));
+    merged.merge(&rate(Aggregate::Rate, &[(0, 1.0)]));
+    assert_eq!(merged.finish(), Some(2.125));
+}
+
+#[test]
+fn test_distributions() {
+    let finish = |aggregate, values: &[f64]| {
+        let mut acc = Accumulator::new(aggregate);
+        for (time, value) in values.iter().enumerate() {
+            acc.push(time as Timestamp, *value);
+        }
+        acc.finish()
+    };
+    let values = [15.0, 20.0, 35.0, 40.0, 50.0];
+    assert_eq!(finish(Aggregate::Percentile(30.0), &values), Some(20.0));
+    assert_eq!(finish(Aggregate::Percentile(100.0), &values), Some(50.0));
+    assert_eq!(finish(Aggregate::Percentile(0.0), &values), Some(15.0));
+    assert_eq!(finish(Aggregate::Median, &values), Some(35.0));
+    assert_eq!(finish(Aggregate::Median, &values[..4]), Some(27.5));
+    assert_eq!(finish(Aggregate::Median, &[]), None);
+    let approx = finish(Aggregate::ApproxPercentile(50.0), &values).unwrap();
+    assert!((approx - 35.0).abs() < 0.35);
+    assert_eq!(finish(Aggregate::Spread, &values), Some(35.0));
+    assert_eq!(finish(Aggregate::AtMost(35.0), &values), Some(3.0));
+    assert_eq!(finish(Aggregate::AtMost(35.0), &[]), Some(0.0));
+    let stddev = finish(Aggregate::Stddev, &[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+    assert!((stddev - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+    assert_eq!(finish(Aggregate::Stddev, &[1.0]), None);
+
+    // Merging gives what a single accumulator would
+    let mut merged = Accumulator::new(Aggregate::Stddev);
+    merged.push(0, 2.0);
+    merged.push(1, 4.0);
+    let mut other = Accumulator::new(Aggregate::Stddev);
+    for value in [4.0, 4.0, 5.0, 5.0, 7.0, 9.0] {
+        other.push(0, value);
+    }
+    merged.merge(&other);
+    let stddev = merged.finish().unwrap();
+    assert!((stddev - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+
+    let args = [Expr::Field("x".to_owned()), Expr::Number(10.0), Expr::Number(100.0)];
+    let (aggregates, arg) = Aggregate::from_call("histogram", &args).unwrap();
+    assert_eq!(
+        aggregates,
+        vec![Aggregate::AtMost(10.0), Aggregate::AtMost(100.0), Aggregate::AtMost(f64::INFINITY)]
+    );
+    assert_eq!(arg, &args[0]);
+    assert!(Aggregate::from_call("percentile", &args).is_err());
+    assert!(Aggregate::from_call("percentile", &[args[0].clone(), Expr::Number(101.0)]).is_err());
+    assert!(Aggregate::from_call("histogram", &[args[0].clone(), Expr::Number(10.0), Expr::Number(5.0)]).is_err());
+    assert!(Aggregate::from_call("max", &args[..2]).is_err());
+}
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::output::Sink;
+use super::{execute_plan, expand_fields, matching_series, Plan};
+use crate::db::counting::CountingDB;
+use crate::db::{Timestamp, DB};
+use crate::parser::select::{Expr, Select, Table};
+
+/// What a SELECT is going to read and, with ANALYZE, what running it took.
+#[derive(Debug)]
+pub struct Explanation {
+    pub time_start: Timestamp,
+    pub time_end: Timestamp,
+    // Seconds the range is read earlier by, from OFFSET
+    pub shift: Timestamp,
+    // Every function called, in the order of the fields
+    pub functions: Vec<String>,
+    // Ids of the series read for each metric. Unknown before a subquery runs,
+    // so left empty then.
+    pub series: Vec<(String, Vec<u64>)>,
+    // Minute buckets per series in the time range, None if it's open
+    pub buckets: Option<u64>,
+    pub subquery: Option<Box<Explanation>>,
+    pub analysis: Option<Analysis>,
+}
+
+#[derive(Debug)]
+pub struct Analysis {
+    pub stages: Vec<Stage>,
+    pub result_series: usize,
+    pub rows: usize,
+}
+
+#[derive(Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    pub time: Duration,
+    pub keys_read: u64,
+}
+
+pub fn explain<D: DB>(db: &D, select: &Select) -> Result<Explanation> {
+    let plan = Plan::new(select)?;
+    let subquery = match &select.table {
+        Table::Subquery(inner) => Some(Box::new(explain(db, inner)?)),
+        Table::Name(_) => None,
+    };
+    let mut functions = Vec::new();
+    let mut metrics = Vec::new();
+    let fields = match subquery {
+        // The metrics are the columns of the subquery, not in `db`
+        Some(_) => select.fields.clone(),
+        None => expand_fields(db, &select.fields)?,
+    };
+    for field in &fields {
+        walk(&field.expr, &mut functions, &mut metrics);
+    }
+
+    let mut series = Vec::new();
+    if subquery.is_none() {
+        for metric in metrics {
+            let ids = matching_series(db, &metric, &plan).iter().map(|series| series.id).collect();
+            series.push((metric, ids));
+        }
+    }
+
+    let buckets = match (plan.time_start, plan.time_end) {
+        (Timestamp::MIN, _) | (_, Timestamp::MAX) => None,
+        (start, end) => Some((end.div_euclid(60) - start.div_euclid(60) + 1).max(0) as u64),
+    };
+    Ok(Explanation {
+        time_start: plan.time_start,
+        time_end: plan.time_end,
+        shift: plan.shift,
+        functions,
+        series,
+        buckets,
+        subquery,
+        analysis: None,
+    })
+}
+
+/// Explains `select`, then runs it with the results thrown away, timing each
+/// stage of execution and counting the keys it reads. Only the run is
+/// counted, not the lookups of the explanation.
+pub fn explain_analyze<D: DB>(db: &D, select: &Select) -> Result<Explanation> {
+    let mut explanation = explain(db, select)?;
+
+    let counting = CountingDB::new(db);
+    let keys_read = || counting.keys_read();
+    let stages = RefCell::new(Stages {
+        keys_read: &keys_read,
+        current: None,
+        done: Vec::new(),
+    });
+    let on_stage = |name| stages.borrow_mut().enter(name);
+    on_stage("plan");
+    let plan = Plan {
+        on_stage: Some(&on_stage),
+        ..Plan::new(select)?
+    };
+    let mut counter = Counter::default();
+    execute_plan(&counting, select, &plan, &mut counter)?;
+    let mut stages = stages.into_inner();
+    stages.leave();
+
+    explanation.analysis = Some(Analysis {
+        stages: stages.done,
+        result_series: counter.series,
+        rows: counter.rows,
+    });
+    Ok(explanation)
+}
+
+// Adds up the time taken and keys read by each stage, as execution moves
+// back and forth between them
+struct Stages<'a> {
+    keys_read: &'a dyn Fn() -> u64,
+    // The stage execution is in, since when and the keys read before it
+    current: Option<(&'static str, Instant, u64)>,
+    done: Vec<Stage>,
+}
+
+impl Stages<'_> {
+    fn enter(&mut self, name: &'static str) {
+        if self.current.map_or(false, |(current, ..)| current == name) {
+            return;
+        }
+        self.leave();
+        self.current = Some((name, Instant::now(), (self.keys_read)()));
+    }
+
+    fn leave(&mut self) {
+        if let Some((name, started, read_before)) = self.current.take() {
+            let (time, keys_read) = (started.elapsed(), (self.keys_read)() - read_before);
+            match self.done.iter_mut().find(|stage| stage.name == name) {
+                Some(stage) => {
+                    stage.time += time;
+                    stage.keys_read += keys_read;
+                }
+                None => self.done.push(Stage { name, time, keys_read }),
+            }
+        }
+    }
+}
+
+// Collects the functions an expression calls and the metrics it reads, each
+// once. Only the first argument of a call is read from, the others being
+// parameters such as tags, counts and durations.
+fn walk(expr: &Expr, functions: &mut Vec<String>, metrics: &mut Vec<String>) {
+    match expr {
+        Expr::Field(metric) if metric != "*" && !metrics.contains(metric) => metrics.push(metric.clone()),
+        Expr::Call { function, args } => {
+            if !functions.contains(function) {
+                functions.push(function.clone());
+            }
+            if let Some(arg) = args.first() {
+                walk(arg, functions, metrics);
+            }
+        }
+        Expr::Binary { left, right, .. } => {
+            walk(left, functions, metrics);
+            walk(right, functions, metrics);
+        }
+        Expr::Negate(inner) => walk(inner, functions, metrics),
+        _ => {}
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bound = |time: Timestamp| match time {
+            Timestamp::MIN | Timestamp::MAX => "open".to_owned(),
+            time => time.to_string(),
+        };
+        writeln!(f, "time range: {} to {}", bound(self.time_start), bound(self.time_end))?;
+        if self.shift != 0 {
+            writeln!(f, "offset: {}s", self.shift)?;
+        }
+        writeln!(f, "functions: {}", self.functions.join(", "))?;
+        // There are no rollups, every query reads the raw minute buckets
+        writeln!(f, "tier: raw")?;
+        match self.buckets {
+            Some(buckets) => writeln!(f, "buckets: {} per series", buckets)?,
+            None => writeln!(f, "buckets: all stored")?,
+        }
+        match &self.subquery {
+            Some(subquery) => {
+                writeln!(f, "series: from the subquery")?;
+                writeln!(f, "subquery:")?;
+                for line in subquery.to_string().lines() {
+                    writeln!(f, "  {}", line)?;
+                }
+            }
+            None => {
+                for (metric, ids) in &self.series {
+                    let ids: Vec<_> = ids.iter().map(u64::to_string).collect();
+                    writeln!(f, "series: {} [{}]", metric, ids.join(", "))?;
+                }
+            }
+        }
+        if let Some(analysis) = &self.analysis {
+            writeln!(f, "stage\ttime\tkeys read")?;
+            for stage in &analysis.stages {
+                writeln!(f, "{}\t{:?}\t{}", stage.name, stage.time, stage.keys_read)?;
+            }
+            writeln!(f, "results: {} series, {} rows", analysis.result_series, analysis.rows)?;
+        }
+        Ok(())
+    }
+}
+
+// Counts the results instead of keeping them
+#[derive(Default)]
+struct Counter {
+    series: usize,
+    rows: usize,
+}
+
+impl Sink for Counter {
+    fn begin_series(&mut self, _: &str, _: &HashMap<String, String>, _: &[String]) -> Result<()> {
+        self.series += 1;
+        Ok(())
+    }
+
+    fn row(&mut self, _: Timestamp, _: &[Option<f64>]) -> Result<()> {
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_explain() {
+    let db = super::test_db(&[
+        ("cpu", &[("host", "a")], 0, 1.0),
+        ("cpu", &[("host", "b")], 0, 2.0),
+        ("cpu", &[("host", "b")], 120, 3.0),
+        ("mem", &[("host", "a")], 0, 4.0),
+    ]);
+    let select = |sql| crate::parser::select::select_parser(sql).unwrap().1;
+
+    let sql = "select moving_average(max(cpu), 2) / 2 from m where host = 'b' and time >= 0 and time < 180 group by time(1m)";
+    let explanation = explain(&db, &select(sql)).unwrap();
+    assert_eq!((explanation.time_start, explanation.time_end), (0, 179));
+    assert_eq!(explanation.functions, vec!["moving_average", "max"]);
+    assert_eq!(explanation.series.len(), 1);
+    assert_eq!(explanation.series[0].1.len(), 1);
+    assert_eq!(explanation.buckets, Some(3));
+    assert!(explanation.analysis.is_none());
+
+    let explanation = explain_analyze(&db, &select(sql)).unwrap();
+    let analysis = explanation.analysis.as_ref().unwrap();
+    let names: Vec<_> = analysis.stages.iter().map(|stage| stage.name).collect();
+    assert_eq!(names, vec!["plan", "series lookup", "scan", "aggregation", "output"]);
+    assert!(analysis.stages[2].keys_read > 0);
+    // Every key is counted once, in the stage that read it
+    let counting = CountingDB::new(&db);
+    super::execute(&counting, &select(sql), &mut Counter::default()).unwrap();
+    let keys_read: u64 = analysis.stages.iter().map(|stage| stage.keys_read).sum();
+    assert_eq!(keys_read, counting.keys_read());
+    assert_eq!((analysis.result_series, analysis.rows), (1, 2));
+    assert!(explanation.to_string().contains("results: 1 series, 2 rows"));
+
+    let explanation = explain_analyze(&db, &select("select cpu from m limit 1")).unwrap();
+    let names: Vec<_> = explanation.analysis.unwrap().stages.iter().map(|stage| stage.name).collect();
+    assert_eq!(names, vec!["plan", "series lookup", "scan", "output"]);
+
+    let explanation = explain(&db, &select("select max(x) from (select cpu + mem as x from m)")).unwrap();
+    assert_eq!(explanation.buckets, None);
+    assert!(explanation.series.is_empty());
+    let subquery = explanation.subquery.as_ref().unwrap();
+    assert_eq!(subquery.series.iter().map(|(metric, _)| metric.as_str()).collect::<Vec<_>>(), vec!["cpu", "mem"]);
+    assert!(explanation.to_string().contains("\n  series: cpu ["));
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+
+use crate::parser::select::{BinaryOperator, Expr};
+
+/// Arithmetic on the values of a row, with every operand reading a metric
+/// replaced by the column holding its values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Column(usize),
+    Constant(f64),
+    Negate(Box<Term>),
+    Binary(BinaryOperator, Box<Term>, Box<Term>),
+}
+
+impl Term {
+    // `operand` is given each metric or call and returns its column
+    pub fn compile<'a>(expr: &'a Expr, operand: &mut dyn FnMut(&'a Expr) -> Result<usize>) -> Result<Term> {
+        Ok(match expr {
+            Expr::Number(number) => Term::Constant(*number),
+            Expr::Duration(seconds) => Term::Constant(*seconds as f64),
+            Expr::Negate(inner) => Term::Negate(Box::new(Term::compile(inner, operand)?)),
+            Expr::Binary { operator, left, right } => Term::Binary(
+                *operator,
+                Box::new(Term::compile(left, operand)?),
+                Box::new(Term::compile(right, operand)?),
+            ),
+            _ => Term::Column(operand(expr)?),
+        })
+    }
+
+    // None if a value it needs is missing, or on division by zero
+    pub fn evaluate(&self, row: &[Option<f64>]) -> Option<f64> {
+        match self {
+            Term::Column(column) => row[*column],
+            Term::Constant(value) => Some(*value),
+            Term::Negate(inner) => inner.evaluate(row).map(|value| -value),
+            Term::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(row)?, right.evaluate(row)?);
+                match operator {
+                    BinaryOperator::Add => Some(left + right),
+                    BinaryOperator::Subtract => Some(left - right),
+                    BinaryOperator::Multiply => Some(left * right),
+                    BinaryOperator::Divide | BinaryOperator::Modulo if right == 0.0 => None,
+                    BinaryOperator::Divide => Some(left / right),
+                    BinaryOperator::Modulo => Some(left % right),
+                }
+            }
+        }
+    }
+}
+
+// Whether there is arithmetic on top of an expression, rather than a single
+// metric or call
+pub fn is_arithmetic(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Binary { .. } | Expr::Negate(_) | Expr::Number(_) | Expr::Duration(_)
+    )
+}
+
+// The metrics and calls the arithmetic of an expression is on, in order
+pub fn operands(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Number(_) | Expr::Duration(_) => Vec::new(),
+        Expr::Negate(inner) => operands(inner),
+        Expr::Binary { left, right, .. } => {
+            let mut output = operands(left);
+            output.extend(operands(right));
+            output
+        }
+        _ => vec![expr],
+    }
+}
+
+#[test]
+fn test_evaluate() {
+    let expr = |sql| {
+        let (_, fields) = crate::parser::select::select_parser(sql).unwrap();
+        fields.fields[0].expr.clone()
+    };
+    let mut columns = Vec::new();
+    let used_percent = expr("select -(used / total * 100) % 7 from m");
+    let term = Term::compile(&used_percent, &mut |operand| {
+        columns.push(operand.to_string());
+        Ok(columns.len() - 1)
+    })
+    .unwrap();
+    assert_eq!(columns, vec!["used", "total"]);
+    assert_eq!(term.evaluate(&[Some(3.0), Some(4.0)]), Some(-5.0));
+    assert_eq!(term.evaluate(&[Some(3.0), None]), None);
+    assert_eq!(term.evaluate(&[Some(3.0), Some(0.0)]), None);
+
+    assert_eq!(operands(&expr("select max(a) * 2 + b from m")).len(), 2);
+    assert!(operands(&expr("select 1 + 1 from m")).is_empty());
+    assert!(is_arithmetic(&expr("select -a from m")));
+    assert!(!is_arithmetic(&expr("select max(a) from m")));
+}
@@ -0,0 +1,156 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+use crate::db::Timestamp;
+use crate::parser::select::{Fill, TimeWindow};
+
+// Filling creates rows out of nothing, so a range far wider than the window
+// must not be able to exhaust memory
+const MAX_FILLED_WINDOWS: i128 = 1_000_000;
+
+pub type Row = (Timestamp, Vec<Option<f64>>);
+
+/// Completes the windowed rows of one result series. Every window from
+/// `first` to `last` is present afterwards, with missing values filled in as
+/// `fill` asks. Without bounds the first and last rows holding data are used.
+pub fn fill_windows(
+    rows: BTreeMap<Timestamp, Vec<Option<f64>>>,
+    columns: usize,
+    fill: Fill,
+    window: &TimeWindow,
+    first: Option<Timestamp>,
+    last: Option<Timestamp>,
+) -> Result<Vec<Row>> {
+    if fill == Fill::None {
+        return Ok(rows.into_iter().collect());
+    }
+    let (first, last) = match (first.or(rows.keys().next().copied()), last.or(rows.keys().last().copied())) {
+        (Some(first), Some(last)) if first <= last => (first, last),
+        _ => return Ok(rows.into_iter().collect()),
+    };
+    let windows = (i128::from(last) - i128::from(first)) / i128::from(window.interval) + 1;
+    if windows > MAX_FILLED_WINDOWS {
+        bail!(
+            "FILL would produce {} windows, narrow the time range or widen the window",
+            windows
+        );
+    }
+
+    let mut output = Vec::with_capacity(windows as usize);
+    let mut start = Some(first);
+    while let Some(time) = start.filter(|time| *time <= last) {
+        let values = rows.get(&time).cloned().unwrap_or_else(|| vec![None; columns]);
+        output.push((time, values));
+        start = time.checked_add(window.interval);
+    }
+
+    for column in 0..columns {
+        fill_column(&mut output, column, fill);
+    }
+    Ok(output)
+}
+
+fn fill_column(rows: &mut [Row], column: usize, fill: Fill) {
+    match fill {
+        Fill::Null | Fill::None => {}
+        Fill::Value(value) => {
+            for (_, values) in rows.iter_mut() {
+                values[column].get_or_insert(value);
+            }
+        }
+        Fill::Previous => {
+            let mut previous = None;
+            for (_, values) in rows.iter_mut() {
+                match values[column] {
+                    Some(value) => previous = Some(value),
+                    None => values[column] = previous,
+                }
+            }
+        }
+        Fill::Linear => {
+            // Only gaps with a known value on both sides are interpolated
+            let mut previous: Option<(Timestamp, f64)> = None;
+            let mut gap = Vec::new();
+            for i in 0..rows.len() {
+                let time = rows[i].0;
+                let value = match rows[i].1[column] {
+                    Some(value) => value,
+                    None => {
+                        gap.push(i);
+                        continue;
+                    }
+                };
+                if let Some((previous_time, previous_value)) = previous {
+                    let slope = (value - previous_value) / (time - previous_time) as f64;
+                    for &j in &gap {
+                        let (gap_time, values) = &mut rows[j];
+                        values[column] = Some(previous_value + slope * (*gap_time - previous_time) as f64);
+                    }
+                }
+                gap.clear();
+                previous = Some((time, value));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fill_windows() {
+    let window = TimeWindow {
+        interval: 60,
+        offset: 0,
+    };
+    let rows = BTreeMap::from([
+        (60, vec![Some(1.0), None]),
+        (240, vec![Some(4.0), Some(2.0)]),
+    ]);
+    let filled = |fill, first, last| fill_windows(rows.clone(), 2, fill, &window, first, last).unwrap();
+
+    assert_eq!(filled(Fill::None, Some(0), None).len(), 2);
+    assert_eq!(
+        filled(Fill::Null, Some(0), None),
+        vec![
+            (0, vec![None, None]),
+            (60, vec![Some(1.0), None]),
+            (120, vec![None, None]),
+            (180, vec![None, None]),
+            (240, vec![Some(4.0), Some(2.0)]),
+        ]
+    );
+    assert_eq!(
+        filled(Fill::Value(0.0), None, Some(300)),
+        vec![
+            (60, vec![Some(1.0), Some(0.0)]),
+            (120, vec![Some(0.0), Some(0.0)]),
+            (180, vec![Some(0.0), Some(0.0)]),
+            (240, vec![Some(4.0), Some(2.0)]),
+            (300, vec![Some(0.0), Some(0.0)]),
+        ]
+    );
+    assert_eq!(
+        filled(Fill::Previous, None, Some(300)),
+        vec![
+            (60, vec![Some(1.0), None]),
+            (120, vec![Some(1.0), None]),
+            (180, vec![Some(1.0), None]),
+            (240, vec![Some(4.0), Some(2.0)]),
+            (300, vec![Some(4.0), Some(2.0)]),
+        ]
+    );
+    assert_eq!(
+        filled(Fill::Linear, Some(0), Some(300)),
+        vec![
+            (0, vec![None, None]),
+            (60, vec![Some(1.0), None]),
+            (120, vec![Some(2.0), None]),
+            (180, vec![Some(3.0), None]),
+            (240, vec![Some(4.0), Some(2.0)]),
+            (300, vec![None, None]),
+        ]
+    );
+
+    assert!(fill_windows(BTreeMap::new(), 1, Fill::Null, &window, Some(0), Some(Timestamp::MAX)).is_err());
+    assert!(fill_windows(BTreeMap::new(), 1, Fill::Null, &window, Some(0), None)
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,165 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::parser::select::{Operator, Predicate};
+
+/// Predicates on the tags of a series, compiled once and then tested against
+/// every series of the metric. All of them must hold.
+#[derive(Debug)]
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+#[derive(Debug)]
+enum Term {
+    Equals { key: String, value: String, negated: bool },
+    Matches { key: String, regex: Regex, negated: bool },
+    And(Box<Term>, Box<Term>),
+    Or(Box<Term>, Box<Term>),
+    Not(Box<Term>),
+}
+
+impl Filter {
+    pub fn new(predicates: &[Predicate]) -> Result<Filter> {
+        let terms = predicates.iter().map(compile).collect::<Result<_>>()?;
+        Ok(Filter { terms })
+    }
+
+    // A missing tag compares as the empty string
+    pub fn matches(&self, tags: &HashMap<String, String>) -> bool {
+        self.terms.iter().all(|term| evaluate(term, tags))
+    }
+}
+
+/// Conditions on the value of every point, checked while scanning. All of
+/// them must hold.
+#[derive(Debug, Default)]
+pub struct ValueFilter {
+    predicates: Vec<Predicate>,
+}
+
+impl ValueFilter {
+    pub fn new(predicates: Vec<Predicate>) -> Result<ValueFilter> {
+        for predicate in &predicates {
+            if !only_values(predicate) {
+                bail!("value conditions can only be combined with other conditions by AND");
+            }
+        }
+        Ok(ValueFilter { predicates })
+    }
+
+    pub fn matches(&self, value: f64) -> bool {
+        self.predicates.iter().all(|predicate| evaluate_value(predicate, value))
+    }
+}
+
+// Whether a predicate is on point values alone, as opposed to tags or time
+pub fn only_values(predicate: &Predicate) -> bool {
+    match predicate {
+        Predicate::Value { .. } => true,
+        Predicate::Condition(_) | Predicate::Time { .. } => false,
+        Predicate::And(left, right) | Predicate::Or(left, right) => only_values(left) && only_values(right),
+        Predicate::Not(inner) => only_values(inner),
+    }
+}
+
+fn evaluate_value(predicate: &Predicate, point: f64) -> bool {
+    match predicate {
+        Predicate::Value { operator, value } => match operator {
+            Operator::Eq => point == *value,
+            Operator::Ne => point != *value,
+            Operator::Gt => point > *value,
+            Operator::Ge => point >= *value,
+            Operator::Lt => point < *value,
+            Operator::Le => point <= *value,
+            Operator::Match | Operator::NotMatch => false,
+        },
+        Predicate::Condition(_) | Predicate::Time { .. } => false,
+        Predicate::And(left, right) => evaluate_value(left, point) && evaluate_value(right, point),
+        Predicate::Or(left, right) => evaluate_value(left, point) || evaluate_value(right, point),
+        Predicate::Not(inner) => !evaluate_value(inner, point),
+    }
+}
+
+// Rejects what can't be decided from a tag set alone: time conditions, which
+// only select a range at the top level of WHERE, value conditions, and
+// ordering on tags
+fn compile(predicate: &Predicate) -> Result<Term> {
+    let boxed = |predicate| compile(predicate).map(Box::new);
+    Ok(match predicate {
+        Predicate::Time { .. } => bail!("time conditions can only be combined with AND"),
+        Predicate::Condition(c) if c.field == "time" => bail!("invalid time `{}`", c.value),
+        Predicate::Value { .. } => {
+            bail!("value conditions can only be combined with other conditions by AND")
+        }
+        Predicate::Condition(c) => match c.operator {
+            Operator::Eq | Operator::Ne => Term::Equals {
+                key: c.field.clone(),
+                value: c.value.clone(),
+                negated: c.operator == Operator::Ne,
+            },
+            Operator::Match | Operator::NotMatch => Term::Matches {
+                key: c.field.clone(),
+                regex: Regex::new(&c.value).with_context(|| format!("invalid regular expression /{}/", c.value))?,
+                negated: c.operator == Operator::NotMatch,
+            },
+            _ => bail!("tag `{}` can only be compared with =, !=, =~ and !~", c.field),
+        },
+        Predicate::And(left, right) => Term::And(boxed(left)?, boxed(right)?),
+        Predicate::Or(left, right) => Term::Or(boxed(left)?, boxed(right)?),
+        Predicate::Not(inner) => Term::Not(boxed(inner)?),
+    })
+}
+
+fn evaluate(term: &Term, tags: &HashMap<String, String>) -> bool {
+    let tag = |key: &String| tags.get(key).map_or("", String::as_str);
+    match term {
+        Term::Equals { key, value, negated } => (tag(key) == value) != *negated,
+        Term::Matches { key, regex, negated } => regex.is_match(tag(key)) != *negated,
+        Term::And(left, right) => evaluate(left, tags) && evaluate(right, tags),
+        Term::Or(left, right) => evaluate(left, tags) || evaluate(right, tags),
+        Term::Not(inner) => !evaluate(inner, tags),
+    }
+}
+
+#[test]
+fn test_matches() {
+    use crate::parser::select::where_parser;
+    let tags = HashMap::from([("host".to_owned(), "web-12".to_owned()), ("dc".to_owned(), "x".to_owned())]);
+    let filter = |sql| {
+        let (_, predicates) = where_parser(sql).unwrap();
+        Filter::new(&predicates)
+    };
+    let matches_where = |sql| filter(sql).unwrap().matches(&tags);
+    assert!(matches_where("where host = 'a' or dc = 'x'"));
+    assert!(matches_where("where dc in ('y', 'x') and host != 'y'"));
+    assert!(matches_where("where not (host = 'b' or dc = 'y')"));
+    assert!(matches_where("where rack != 'r'"));
+    assert!(!matches_where("where dc = 'x' and not dc in ('x')"));
+    assert!(matches_where("where host =~ /^web-\\d+$/ and dc !~ /y/"));
+    assert!(matches_where("where rack =~ /^$/"));
+    assert!(!matches_where("where host !~ /web/"));
+
+    assert!(filter("where host = 'a' or time > 10").is_err());
+    assert!(filter("where host > 'a'").is_err());
+    assert!(filter("where host =~ /(/").is_err());
+}
+
+#[test]
+fn test_value_filter() {
+    use crate::parser::select::where_parser;
+    let filter = |sql| {
+        let (_, predicates) = where_parser(sql).unwrap();
+        ValueFilter::new(predicates)
+    };
+    let between = filter("where value between 10 and 20 and not value = 15").unwrap();
+    assert!(between.matches(10.0));
+    assert!(between.matches(20.0));
+    assert!(!between.matches(15.0));
+    assert!(!between.matches(20.5));
+    assert!(filter("where value < 0 or value > 100").unwrap().matches(-1.0));
+    assert!(ValueFilter::default().matches(f64::NAN));
+
+    assert!(filter("where value > 1 or host = 'a'").is_err());
+}
@@ -0,0 +1,1347 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::iter::Peekable;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::series::Series;
+use crate::db::Timestamp;
+use crate::parser::select::{Expr, Fill, Operator, Order, Predicate, Select, SelectField, Table, TagGrouping};
+use crate::parser::time::{TimeExpr, NANOS_IN_SECOND};
+
+pub mod aggregate;
+pub mod explain;
+pub mod expression;
+pub mod fill;
+pub mod filter;
+pub mod output;
+pub mod schema;
+pub mod sketch;
+pub mod source;
+pub mod top;
+pub mod transform;
+pub mod window;
+
+use aggregate::{Accumulator, Aggregate};
+use expression::Term;
+use output::Sink;
+use source::{Points, Results, Source};
+use transform::{Transform, Transformer};
+
+/// What a SELECT reads: the time range, the predicates on the tags of every
+/// series read and those on the values of their points. Tags fixed by a top
+/// level `=` are kept apart to label the results with.
+pub struct Plan<'a> {
+    pub time_start: Timestamp,
+    pub time_end: Timestamp,
+    pub filter: filter::Filter,
+    pub values: filter::ValueFilter,
+    pub tags: HashMap<String, String>,
+    // Seconds every point is read earlier by, then moved forward by
+    pub shift: Timestamp,
+    // Told the name of each stage of execution as it moves on to it, for
+    // EXPLAIN ANALYZE: series lookup, scan, aggregation or output
+    pub on_stage: Option<&'a dyn Fn(&'static str)>,
+}
+
+impl Plan<'_> {
+    pub fn new(select: &Select) -> Result<Plan<'static>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as i128);
+        Plan::at(select, now)
+    }
+
+    // Plans with `now`, in nanoseconds since the epoch, as the time of now()
+    pub fn at(select: &Select, now: i128) -> Result<Plan<'static>> {
+        let mut time_start = Timestamp::MIN;
+        let mut time_end = Timestamp::MAX;
+        let mut predicates = Vec::new();
+        let mut value_predicates = Vec::new();
+        let mut tags = HashMap::new();
+        for predicate in &select.conditions {
+            match predicate {
+                Predicate::Time { operator, time } => {
+                    let (start, end) = time_bounds(*operator, time.resolve(now))?;
+                    time_start = cmp::max(time_start, start);
+                    time_end = cmp::min(time_end, end);
+                }
+                Predicate::Condition(c) if c.field == "time" => bail!("invalid time `{}`", c.value),
+                Predicate::Condition(c) => {
+                    if c.operator == Operator::Eq {
+                        tags.insert(c.field.clone(), c.value.clone());
+                    }
+                    predicates.push(predicate.clone());
+                }
+                _ if filter::only_values(predicate) => value_predicates.push(predicate.clone()),
+                _ => predicates.push(predicate.clone()),
+            }
+        }
+        Ok(Plan {
+            time_start,
+            time_end,
+            filter: filter::Filter::new(&predicates)?,
+            values: filter::ValueFilter::new(value_predicates)?,
+            tags,
+            shift: select.shift,
+            on_stage: None,
+        })
+    }
+
+    fn stage(&self, name: &'static str) {
+        if let Some(on_stage) = self.on_stage {
+            on_stage(name);
+        }
+    }
+
+    // Aggregates over the whole range are reported at its start, or at the
+    // epoch if it has none
+    fn row_time(&self) -> Timestamp {
+        if self.time_start == Timestamp::MIN {
+            0
+        } else {
+            self.time_start
+        }
+    }
+}
+
+// The first and last second satisfying `time <operator> nanos`, where a
+// bound between two seconds leaves out the one on the wrong side
+fn time_bounds(operator: Operator, nanos: i128) -> Result<(Timestamp, Timestamp)> {
+    let floor = nanos.div_euclid(NANOS_IN_SECOND);
+    let ceil = floor + i128::from(nanos.rem_euclid(NANOS_IN_SECOND) != 0);
+    let (start, end) = match operator {
+        Operator::Ge => (ceil, i128::MAX),
+        Operator::Gt => (floor + 1, i128::MAX),
+        Operator::Le => (i128::MIN, floor),
+        Operator::Lt => (i128::MIN, ceil - 1),
+        Operator::Eq => (ceil, floor),
+        _ => bail!("time {:?} is not supported", operator),
+    };
+    let clamp = |time: i128| time.clamp(i128::from(Timestamp::MIN), i128::from(Timestamp::MAX)) as Timestamp;
+    Ok((clamp(start), clamp(end)))
+}
+
+pub fn execute<S: Source>(db: &S, select: &Select, sink: &mut dyn Sink) -> Result<()> {
+    execute_plan(db, select, &Plan::new(select)?, sink)
+}
+
+// Runs `select` as planned by `plan`
+pub fn execute_plan<S: Source>(db: &S, select: &Select, plan: &Plan, sink: &mut dyn Sink) -> Result<()> {
+    if let Table::Subquery(inner) = &select.table {
+        return execute_outer(db, inner, select, plan, sink);
+    }
+    if select.fields.iter().any(|field| top::is_selector(&field.expr)) {
+        return top::execute_top(db, select, plan, sink);
+    }
+    plan.stage("series lookup");
+    let operands: Vec<_> = select
+        .fields
+        .iter()
+        .flat_map(|field| expression::operands(&field.expr))
+        .collect();
+    let aggregated = operands.iter().filter(|operand| is_aggregated(operand)).count();
+    if aggregated == 0 {
+        if select.group_by.time.is_some() {
+            bail!("GROUP BY time needs aggregated fields");
+        }
+        if select.fill != Fill::None {
+            bail!("FILL needs GROUP BY time");
+        }
+        execute_raw(db, select, &expand_fields(db, &select.fields)?, plan, sink)
+    } else if aggregated == operands.len() {
+        if select.fill != Fill::None && select.group_by.time.is_none() {
+            bail!("FILL needs GROUP BY time");
+        }
+        let fields = expand_fields(db, &select.fields)?;
+        if fields.is_empty() {
+            return Ok(());
+        }
+        execute_aggregates(db, select, &fields, plan, None, sink)
+    } else {
+        bail!("cannot mix aggregated and raw fields")
+    }
+}
+
+// Runs `outer` on the results of `inner`, read as series. The time range
+// `outer` reads is passed down to `inner` where that leaves the results in
+// it the same.
+fn execute_outer<S: Source>(db: &S, inner: &Select, outer: &Select, plan: &Plan, sink: &mut dyn Sink) -> Result<()> {
+    let mut inner = inner.clone();
+    if let Some((start, end)) = inner_range(&inner, outer, plan) {
+        let mut bound = |operator, time: Timestamp| {
+            inner.conditions.push(Predicate::Time {
+                operator,
+                time: TimeExpr::Absolute(i128::from(time) * NANOS_IN_SECOND),
+            })
+        };
+        if start != Timestamp::MIN {
+            bound(Operator::Ge, start);
+        }
+        if end != Timestamp::MAX {
+            bound(Operator::Le, end);
+        }
+    }
+    let inner_plan = Plan {
+        on_stage: plan.on_stage,
+        ..Plan::new(&inner)?
+    };
+    let mut results = Vec::<output::ResultSeries>::new();
+    execute_plan(db, &inner, &inner_plan, &mut results)?;
+
+    let outer = Select {
+        table: Table::Name(inner.table.name().to_owned()),
+        ..outer.clone()
+    };
+    execute_plan(&Results::new(results), &outer, plan, sink)
+}
+
+// The times of the results of `inner` that `outer` reads, and that `inner`
+// can be limited to without changing its results there. None if `inner`
+// needs to see everything: its transforms, rankings, fills from other windows
+// and row limits depend on points outside the range, as does an aggregate
+// over the whole of it. Points `outer` reads with time_shift are earlier, so
+// it reads everything then.
+fn inner_range(inner: &Select, outer: &Select, plan: &Plan) -> Option<(Timestamp, Timestamp)> {
+    if inner.limit.is_some() || inner.offset != 0 || matches!(inner.fill, Fill::Previous | Fill::Linear) {
+        return None;
+    }
+    let reads_history = |call: &Expr| match call {
+        Expr::Call { function, args } => {
+            top::is_selector(call) || !matches!(Transform::from_call(function, args), Ok(None))
+        }
+        _ => false,
+    };
+    let is_time_shift = |call: &Expr| matches!(call, Expr::Call { function, .. } if function == "time_shift");
+    if inner.fields.iter().any(|field| calls(&field.expr, &reads_history))
+        || outer.fields.iter().any(|field| calls(&field.expr, &is_time_shift))
+    {
+        return None;
+    }
+    // As scan_points reads them
+    let earlier = |time: Timestamp| match time {
+        Timestamp::MIN | Timestamp::MAX => time,
+        _ => time.saturating_sub(plan.shift),
+    };
+    let (start, end) = (earlier(plan.time_start), earlier(plan.time_end));
+    let aggregated = inner
+        .fields
+        .iter()
+        .flat_map(|field| expression::operands(&field.expr))
+        .any(is_aggregated);
+    match (aggregated, &inner.group_by.time) {
+        (false, _) => Some((start, end)),
+        (true, None) => None,
+        // The last window read is reported at its start, and takes the
+        // points up to its end
+        (true, Some(window)) => Some(match end {
+            Timestamp::MAX => (start, end),
+            _ => (start, window::window_start(window, end).saturating_add(window.interval - 1)),
+        }),
+    }
+}
+
+// Whether `expr` makes a call `found` is true of, at any depth
+fn calls(expr: &Expr, found: &dyn Fn(&Expr) -> bool) -> bool {
+    match expr {
+        Expr::Call { args, .. } => found(expr) || args.iter().any(|arg| calls(arg, found)),
+        Expr::Binary { left, right, .. } => calls(left, found) || calls(right, found),
+        Expr::Negate(inner) => calls(inner, found),
+        _ => false,
+    }
+}
+
+// Whether a field gives a value per window rather than per point
+fn is_aggregated(field: &Expr) -> bool {
+    match field {
+        Expr::Call { function, .. } if Aggregate::is_aggregate(function) => true,
+        Expr::Call { args, .. } => args.first().map_or(false, is_aggregated),
+        _ => false,
+    }
+}
+
+// Replaces every /regex/ field, and every call on one, with the metrics it
+// matches in name order. Metrics are only listed if there is a regex.
+fn expand_fields<S: Source>(db: &S, fields: &[SelectField]) -> Result<Vec<SelectField>> {
+    let mut metrics: Option<BTreeSet<String>> = None;
+    let mut output = Vec::new();
+    for field in fields {
+        let pattern = match field_regex(&field.expr) {
+            Some(pattern) => pattern,
+            None => {
+                output.push(field.clone());
+                continue;
+            }
+        };
+
+        let regex = Regex::new(pattern).with_context(|| format!("invalid regular expression /{}/", pattern))?;
+        let metrics = metrics.get_or_insert_with(|| db.series_of(None).map(|series| series.metric).collect());
+        for metric in metrics.iter().filter(|metric| regex.is_match(metric)) {
+            output.push(SelectField {
+                expr: replace_regex(&field.expr, metric),
+                alias: field.alias.clone(),
+            });
+        }
+    }
+    Ok(output)
+}
+
+// The regex a field reads its metrics from, through any calls on it
+fn field_regex(field: &Expr) -> Option<&str> {
+    match field {
+        Expr::Regex(pattern) => Some(pattern),
+        Expr::Call { args, .. } => field_regex(args.first()?),
+        _ => None,
+    }
+}
+
+fn replace_regex(field: &Expr, metric: &str) -> Expr {
+    match field {
+        Expr::Regex(_) => Expr::Field(metric.to_owned()),
+        Expr::Call { function, args } => Expr::Call {
+            function: function.clone(),
+            args: args
+                .iter()
+                .enumerate()
+                .map(|(i, arg)| if i == 0 { replace_regex(arg, metric) } else { arg.clone() })
+                .collect(),
+        },
+        _ => field.clone(),
+    }
+}
+
+// What a raw result series reads
+enum RawSource {
+    Points(RawRead, Series),
+    // Arithmetic on series of the same tags, an operand each
+    Expression(Term, Vec<(RawRead, Series)>),
+}
+
+// How the points of a raw operand are read
+#[derive(Clone, Copy, Default)]
+struct RawRead {
+    transform: Option<Transform>,
+    // Seconds earlier than the query, from time_shift
+    shift: Timestamp,
+}
+
+// Streams the points of every matching series, one result series each. Tag
+// grouping changes nothing here as every series is already on its own. Scans
+// stop as soon as LIMIT is reached, so the newest points are cheap with DESC.
+// Arithmetic is on series with the same tags, a result series per tag set
+// that every metric has, and a point per time that all of them have.
+fn execute_raw<S: Source>(
+    db: &S,
+    select: &Select,
+    fields: &[SelectField],
+    plan: &Plan,
+    sink: &mut dyn Sink,
+) -> Result<()> {
+    let mut selected = Vec::new();
+    for field in fields {
+        let column = field.alias.clone().unwrap_or_else(|| field.expr.to_string());
+        if !expression::is_arithmetic(&field.expr) {
+            let (read, metric) = resolve_raw(&field.expr)?;
+            for series in matching_series(db, metric, plan) {
+                selected.push((series.metric.clone(), column.clone(), RawSource::Points(read, series)));
+            }
+            continue;
+        }
+
+        let mut operands = Vec::new();
+        let term = Term::compile(&field.expr, &mut |operand| {
+            operands.push(resolve_raw(operand)?);
+            Ok(operands.len() - 1)
+        })?;
+        if operands.is_empty() {
+            bail!("{} reads no metric", field.expr);
+        }
+        let mut by_tags = BTreeMap::<_, Vec<_>>::new();
+        for (read, metric) in &operands {
+            for series in matching_series(db, metric, plan) {
+                let key = group_key(&series.tags, &TagGrouping::All);
+                by_tags.entry(key).or_default().push((*read, series));
+            }
+        }
+        for (_, series) in by_tags {
+            // A metric has at most one series with the same tags
+            if series.len() == operands.len() {
+                let source = RawSource::Expression(term.clone(), series);
+                selected.push((select.table.name().to_owned(), column.clone(), source));
+            }
+        }
+    }
+
+    for (name, column, source) in paginate(selected.into_iter(), select.soffset, select.slimit) {
+        plan.stage("scan");
+        let points = match &source {
+            RawSource::Points(read, series) => {
+                sink.begin_series(&name, &series.tags, &[column])?;
+                raw_points(db, series, *read, plan, select.order)?
+            }
+            RawSource::Expression(term, operands) => {
+                sink.begin_series(&name, &operands[0].1.tags, &[column])?;
+                let points = operands
+                    .iter()
+                    .map(|(read, series)| Ok(raw_points(db, series, *read, plan, select.order)?.peekable()))
+                    .collect::<Result<_>>()?;
+                join_points(points, term.clone(), select.order)
+            }
+        };
+        for point in paginate(scanned(plan, points, "output"), select.offset, select.limit) {
+            let (time, value) = point?;
+            sink.row(time, &[Some(value)])?;
+        }
+    }
+    Ok(())
+}
+
+// The points of a raw operand in `order`, transformed if asked
+fn raw_points<'a, S: Source>(
+    db: &'a S,
+    series: &Series,
+    read: RawRead,
+    plan: &'a Plan,
+    order: Order,
+) -> Result<Points<'a>> {
+    let transform = match read.transform {
+        Some(transform) => transform,
+        None => return Ok(Box::new(scan_points(db, series, plan, read.shift, order)?)),
+    };
+    let mut transformer = Transformer::new(transform);
+    let transformed = scan_points(db, series, plan, read.shift, Order::Asc)?.filter_map(move |point| match point {
+        Ok((time, value)) => transformer.push(time, value).map(|value| Ok((time, value))),
+        Err(e) => Some(Err(e)),
+    });
+    Ok(match order {
+        Order::Asc => Box::new(transformed),
+        // Transforms need the points in time order, so the results are
+        // reversed instead
+        Order::Desc => Box::new(transformed.collect::<Vec<_>>().into_iter().rev()),
+    })
+}
+
+// Evaluates `term` at every time all the operands have a point, the operands
+// being in `order`. Operands are read in step, so that LIMIT stops the scans.
+fn join_points(mut operands: Vec<Peekable<Points<'_>>>, term: Term, order: Order) -> Points<'_> {
+    Box::new(std::iter::from_fn(move || loop {
+        // The latest time any operand is at, in order, which the others
+        // catch up to
+        let mut target = None;
+        for operand in operands.iter_mut() {
+            let time = match operand.peek()? {
+                Ok((time, _)) => *time,
+                Err(_) => return operand.next(),
+            };
+            target = Some(match (target, order) {
+                (None, _) => time,
+                (Some(target), Order::Asc) => cmp::max(target, time),
+                (Some(target), Order::Desc) => cmp::min(target, time),
+            });
+        }
+        let target = target?;
+
+        let row: Vec<_> = operands
+            .iter_mut()
+            .filter_map(|operand| match operand.peek() {
+                Some(Ok((time, value))) if *time == target => Some(Some(*value)),
+                _ => None,
+            })
+            .collect();
+        if row.len() == operands.len() {
+            for operand in operands.iter_mut() {
+                operand.next();
+            }
+            if let Some(value) = term.evaluate(&row) {
+                return Some(Ok((target, value)));
+            }
+        } else {
+            for operand in operands.iter_mut() {
+                if matches!(operand.peek(), Some(Ok((time, _))) if *time != target) {
+                    operand.next();
+                }
+            }
+        }
+    }))
+}
+
+// Folds every selected metric into its aggregate, giving a result series per
+// group and in it a row per time window, or a single row without GROUP BY
+// time. Each series is folded on its own and merged into its group after,
+// then empty windows are filled in as asked and transforms applied. The
+// arithmetic of the fields is last, on the values of each row. Given `only`
+// groups, the others are skipped and those reported in its order.
+fn execute_aggregates<S: Source>(
+    db: &S,
+    select: &Select,
+    fields: &[SelectField],
+    plan: &Plan,
+    only: Option<&[GroupKey]>,
+    sink: &mut dyn Sink,
+) -> Result<()> {
+    let window = select.group_by.time.as_ref();
+    let mut aggregates = Vec::new();
+    let mut outputs = Vec::new();
+    for field in fields {
+        if expression::is_arithmetic(&field.expr) {
+            let term = Term::compile(&field.expr, &mut |operand| {
+                let mut columns = resolve_aggregate(operand)?;
+                if columns.len() != 1 {
+                    bail!("{} cannot be used in arithmetic", operand);
+                }
+                aggregates.push(columns.remove(0));
+                Ok(aggregates.len() - 1)
+            })?;
+            outputs.push((field.alias.clone().unwrap_or_else(|| field.expr.to_string()), term));
+            continue;
+        }
+        for column in resolve_aggregate(&field.expr)? {
+            let name = match &field.alias {
+                Some(alias) => column.name.replacen(&field.expr.to_string(), alias, 1),
+                None => column.name.clone(),
+            };
+            outputs.push((name, Term::Column(aggregates.len())));
+            aggregates.push(column);
+        }
+    }
+    let columns: Vec<String> = outputs.iter().map(|(name, _)| name.clone()).collect();
+    // Every series is scanned once for all the columns on its metric with
+    // the same time shift
+    let mut by_metric = BTreeMap::<(&str, Timestamp), Vec<usize>>::new();
+    for (i, column) in aggregates.iter().enumerate() {
+        by_metric.entry((column.metric, column.shift)).or_default().push(i);
+    }
+
+    let mut groups = BTreeMap::<GroupKey, BTreeMap<Timestamp, Vec<Option<Accumulator>>>>::new();
+    match only {
+        Some(keys) => groups.extend(keys.iter().map(|key| (key.clone(), BTreeMap::new()))),
+        // Reported even if no series matches
+        None if select.group_by.tags == TagGrouping::None => {
+            groups.insert(Vec::new(), BTreeMap::new());
+        }
+        None => {}
+    }
+    for ((metric, shift), indexes) in &by_metric {
+        let metric_aggregates: Vec<_> = indexes.iter().map(|i| aggregates[*i].aggregate).collect();
+        plan.stage("series lookup");
+        for series in matching_series(db, metric, plan) {
+            let key = group_key(&series.tags, &select.group_by.tags);
+            let rows = match only {
+                Some(_) => match groups.get_mut(&key) {
+                    Some(rows) => rows,
+                    None => continue,
+                },
+                None => groups.entry(key).or_default(),
+            };
+            let points = scanned(plan, scan_points(db, &series, plan, *shift, Order::Asc)?, "aggregation");
+            for (start, accs) in window::fold_windows(points, &metric_aggregates, window, plan.row_time())? {
+                let row = rows.entry(start).or_insert_with(|| vec![None; aggregates.len()]);
+                for (i, acc) in indexes.iter().zip(accs) {
+                    match &mut row[*i] {
+                        Some(merged) => merged.merge(&acc),
+                        slot => *slot = Some(acc),
+                    }
+                }
+            }
+        }
+    }
+
+    let groups: Vec<_> = match only {
+        Some(keys) => keys.iter().filter_map(|key| groups.remove_entry(key)).collect(),
+        None => groups.into_iter().collect(),
+    };
+    for (key, mut rows) in paginate(groups.into_iter(), select.soffset, select.slimit) {
+        plan.stage("aggregation");
+        if window.is_none() {
+            rows.entry(plan.row_time()).or_insert_with(|| vec![None; aggregates.len()]);
+        }
+        let mut tags = plan.tags.clone();
+        tags.extend(key);
+        let finished: BTreeMap<_, _> = rows
+            .into_iter()
+            .map(|(time, accs)| {
+                let values: Vec<_> = accs
+                    .iter()
+                    .zip(&aggregates)
+                    .map(|(acc, column)| match acc {
+                        Some(acc) => acc.finish(),
+                        // Windows without points are left out, so only the
+                        // whole range can be empty: COUNT still reports 0 there
+                        None if window.is_none() => Accumulator::new(column.aggregate).finish(),
+                        None => None,
+                    })
+                    .collect();
+                (time, values)
+            })
+            .collect();
+        let mut finished = match window {
+            Some(window) => fill::fill_windows(
+                finished,
+                aggregates.len(),
+                select.fill,
+                window,
+                (plan.time_start != Timestamp::MIN).then(|| window::window_start(window, plan.time_start)),
+                (plan.time_end != Timestamp::MAX).then(|| window::window_start(window, plan.time_end)),
+            )?,
+            None => finished.into_iter().collect(),
+        };
+        for (i, column) in aggregates.iter().enumerate() {
+            if let Some(transform) = column.transform {
+                let mut transformer = Transformer::new(transform);
+                for (time, values) in finished.iter_mut() {
+                    values[i] = values[i].and_then(|value| transformer.push(*time, value));
+                }
+            }
+        }
+        if select.order == Order::Desc {
+            finished.reverse();
+        }
+
+        plan.stage("output");
+        sink.begin_series(select.table.name(), &tags, &columns)?;
+        for (time, values) in paginate(finished.into_iter(), select.offset, select.limit) {
+            let values: Vec<_> = outputs.iter().map(|(_, term)| term.evaluate(&values)).collect();
+            sink.row(time, &values)?;
+        }
+    }
+    Ok(())
+}
+
+// The points of a series in the time range of the plan that pass its value
+// conditions. Shifted by `shift` seconds on top of the plan, the range is
+// read that much earlier and the points moved forward into it.
+fn scan_points<'a, S: Source>(
+    db: &'a S,
+    series: &Series,
+    plan: &'a Plan,
+    shift: Timestamp,
+    order: Order,
+) -> Result<impl Iterator<Item = Result<(Timestamp, f64)>> + 'a> {
+    let shift = plan.shift.saturating_add(shift);
+    // Open ends stay open
+    let earlier = |time: Timestamp| match time {
+        Timestamp::MIN | Timestamp::MAX => time,
+        _ => time.saturating_sub(shift),
+    };
+    let (start, end) = (earlier(plan.time_start), earlier(plan.time_end));
+    Ok(db.points(series, start, end, order)?.filter_map(move |point| match point {
+        Ok((time, value)) if plan.values.matches(value) => Some(Ok((time.saturating_add(shift), value))),
+        Ok(_) => None,
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+// Reading each of `points` is the scan stage, and what is done with it
+// after is the stage `then`
+fn scanned<'a, T>(
+    plan: &'a Plan,
+    mut points: impl Iterator<Item = T> + 'a,
+    then: &'static str,
+) -> impl Iterator<Item = T> + 'a {
+    std::iter::from_fn(move || {
+        plan.stage("scan");
+        let point = points.next();
+        plan.stage(then);
+        point
+    })
+}
+
+// Skips `offset` items, then stops after `limit` of them
+fn paginate<T>(items: impl Iterator<Item = T>, offset: usize, limit: Option<usize>) -> impl Iterator<Item = T> {
+    items.skip(offset).take(limit.unwrap_or(usize::MAX))
+}
+
+// Every series of `metric` whose tags satisfy the plan
+fn matching_series<S: Source>(db: &S, metric: &str, plan: &Plan) -> Vec<Series> {
+    db.series_of(Some(metric))
+        .filter(|series| plan.filter.matches(&series.tags))
+        .collect()
+}
+
+type GroupKey = Vec<(String, String)>;
+
+// The GROUP BY tags of a series and their values, sorted by key. Series
+// missing a tag are grouped together.
+fn group_key(tags: &HashMap<String, String>, grouping: &TagGrouping) -> GroupKey {
+    let mut key: Vec<_> = match grouping {
+        TagGrouping::None => Vec::new(),
+        TagGrouping::Keys(keys) => keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), tags.get(key)?.clone())))
+            .collect(),
+        TagGrouping::All => tags.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+    };
+    key.sort();
+    key.dedup();
+    key
+}
+
+fn field_metric(field: &Expr) -> Result<&str> {
+    match field {
+        Expr::Field(metric) => Ok(metric),
+        _ => bail!("expected a metric, found {}", field),
+    }
+}
+
+// time_shift(x, duration), x read `duration` earlier
+fn time_shift(args: &[Expr]) -> Result<(Timestamp, &Expr)> {
+    match args {
+        [arg, Expr::Duration(shift)] => Ok((*shift, arg)),
+        _ => bail!("time_shift takes a field and a duration"),
+    }
+}
+
+fn add_shift(shift: Timestamp, more: Timestamp) -> Result<Timestamp> {
+    shift.checked_add(more).context("time_shift is out of range")
+}
+
+// How a raw field is read, and the metric it reads
+fn resolve_raw(field: &Expr) -> Result<(RawRead, &str)> {
+    let (function, args) = match field {
+        Expr::Call { function, args } => (function, args),
+        _ => return Ok((RawRead::default(), field_metric(field)?)),
+    };
+    if function == "time_shift" {
+        let (shift, arg) = time_shift(args)?;
+        let (mut read, metric) = resolve_raw(arg)?;
+        read.shift = add_shift(read.shift, shift)?;
+        return Ok((read, metric));
+    }
+    match Transform::from_call(function, args)? {
+        Some((transform, arg)) => {
+            let (mut read, metric) = resolve_raw(arg)?;
+            if read.transform.is_some() {
+                bail!("{} cannot be applied to {}", function, arg);
+            }
+            read.transform = Some(transform);
+            Ok((read, metric))
+        }
+        None => bail!("unknown function {}", function),
+    }
+}
+
+// An aggregated column of the results
+struct Column<'a> {
+    name: String,
+    aggregate: Aggregate,
+    metric: &'a str,
+    // Applied to the aggregated values after
+    transform: Option<Transform>,
+    // Seconds earlier than the query the metric is read, from time_shift
+    shift: Timestamp,
+}
+
+// The columns of an aggregated field, several for a histogram
+fn resolve_aggregate(field: &Expr) -> Result<Vec<Column<'_>>> {
+    let (function, args) = match field {
+        Expr::Call { function, args } => (function, args),
+        _ => bail!("expected an aggregate, found {}", field),
+    };
+    if function == "time_shift" {
+        let (shift, arg) = time_shift(args)?;
+        let mut columns = resolve_aggregate(arg)?;
+        for column in &mut columns {
+            column.shift = add_shift(column.shift, shift)?;
+            column.name = column.name.replacen(&arg.to_string(), &field.to_string(), 1);
+        }
+        return Ok(columns);
+    }
+    if let Some((transform, arg)) = Transform::from_call(function, args)? {
+        let mut columns = resolve_aggregate(arg)?;
+        for column in &mut columns {
+            if column.transform.is_some() {
+                bail!("{} cannot be applied to {}", function, arg);
+            }
+            column.transform = Some(transform);
+            column.name = column.name.replacen(&arg.to_string(), &field.to_string(), 1);
+        }
+        return Ok(columns);
+    }
+
+    let (aggregates, arg) = Aggregate::from_call(function, args)?;
+    let metric = field_metric(arg)?;
+    Ok(aggregates
+        .into_iter()
+        .map(|aggregate| Column {
+            name: match aggregate {
+                Aggregate::AtMost(bound) if bound.is_infinite() => format!("{}{{le=+Inf}}", field),
+                Aggregate::AtMost(bound) => format!("{}{{le={}}}", field, bound),
+                _ => field.to_string(),
+            },
+            aggregate,
+            metric,
+            transform: None,
+            shift: 0,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+use crate::db::{datapoint::Datapoint, memory::MemoryDB, DB};
+
+#[cfg(test)]
+fn run(db: &MemoryDB, sql: &str) -> Result<Vec<output::ResultSeries>> {
+    let (_, select) = crate::parser::select::select_parser(sql).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    let mut results = Vec::new();
+    execute(db, &select, &mut results)?;
+    Ok(results)
+}
+
+// Metric, tags, time and value of a point to store for a test
+#[cfg(test)]
+type TestPoint<'a> = (&'a str, &'a [(&'a str, &'a str)], Timestamp, f64);
+
+#[cfg(test)]
+fn test_db(points: &[TestPoint]) -> MemoryDB {
+    let db = MemoryDB::default();
+    for (metric, tags, time, value) in points {
+        db.put_datapoint(Datapoint {
+            metric: metric.to_string(),
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            time: *time,
+            value: *value,
+        })
+        .unwrap();
+    }
+    db
+}
+
+#[test]
+fn test_raw() {
+    let db = test_db(&[
+        ("cpu", &[], 60, 1.0),
+        ("cpu", &[], 61, 2.0),
+        ("cpu", &[("host", "a")], 61, 9.0),
+        ("cpu2", &[], 61, 5.0),
+    ]);
+    let results = run(&db, "select cpu from m where time > 60").unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].columns, vec!["cpu"]);
+    assert_eq!(results[0].rows, vec![(61, vec![Some(2.0)])]);
+    assert_eq!(results[1].tags["host"], "a");
+    assert_eq!(results[1].rows, vec![(61, vec![Some(9.0)])]);
+
+    let results = run(&db, "select cpu from m where host = 'a'").unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].rows, vec![(61, vec![Some(9.0)])]);
+}
+
+#[test]
+fn test_aggregates() {
+    let db = test_db(&[("cpu", &[], 60, 1.0), ("cpu", &[], 61, 2.0), ("cpu", &[], 200, 6.0), ("mem", &[], 60, 5.0)]);
+    let results = run(&db, "select count(cpu), avg(cpu), max(cpu), first(cpu), last(mem) from m").unwrap();
+    assert_eq!(results[0].columns, vec!["count(cpu)", "avg(cpu)", "max(cpu)", "first(cpu)", "last(mem)"]);
+    assert_eq!(
+        results[0].rows,
+        vec![(0, vec![Some(3.0), Some(3.0), Some(6.0), Some(1.0), Some(5.0)])]
+    );
+
+    let results = run(&db, "select sum(cpu), min(disk) from m where time >= 61 and time < 200").unwrap();
+    assert_eq!(results[0].rows, vec![(61, vec![Some(2.0), None])]);
+
+    assert!(run(&db, "select cpu, max(cpu) from m").is_err());
+    assert!(run(&db, "select foo(cpu) from m").is_err());
+}
+
+#[test]
+fn test_group_by_time() {
+    let db = test_db(&[
+        ("cpu", &[], 0, 1.0),
+        ("cpu", &[], 100, 3.0),
+        ("cpu", &[], 400, 5.0),
+        ("mem", &[], 650, 2.0),
+    ]);
+    let results = run(&db, "select avg(cpu), count(mem) from m group by time(5m)").unwrap();
+    assert_eq!(
+        results[0].rows,
+        vec![
+            (0, vec![Some(2.0), None]),
+            (300, vec![Some(5.0), None]),
+            (600, vec![None, Some(1.0)]),
+        ]
+    );
+
+    let results = run(&db, "select max(cpu) from m where time >= 100 group by time(5m, 1m)").unwrap();
+    assert_eq!(results[0].rows, vec![(60, vec![Some(3.0)]), (360, vec![Some(5.0)])]);
+
+    assert!(run(&db, "select cpu from m group by time(5m)").is_err());
+}
+
+#[test]
+fn test_group_by_tags() {
+    let db = test_db(&[
+        ("cpu", &[("host", "a"), ("dc", "x")], 0, 1.0),
+        ("cpu", &[("host", "a"), ("dc", "y")], 0, 3.0),
+        ("cpu", &[("host", "b"), ("dc", "x")], 0, 5.0),
+        ("cpu", &[("host", "b"), ("dc", "x")], 400, 7.0),
+    ]);
+    let results = run(&db, "select avg(cpu) from m").unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(4.0)])]);
+
+    let results = run(&db, "select avg(cpu), count(cpu) from m group by host").unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].tags, HashMap::from([("host".to_owned(), "a".to_owned())]));
+    assert_eq!(results[0].rows, vec![(0, vec![Some(2.0), Some(2.0)])]);
+    assert_eq!(results[1].tags["host"], "b");
+    assert_eq!(results[1].rows, vec![(0, vec![Some(6.0), Some(2.0)])]);
+
+    let results = run(&db, "select sum(cpu) from m where dc = 'x' group by host, time(5m)").unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1].tags.len(), 2);
+    assert_eq!(results[1].rows, vec![(0, vec![Some(5.0)]), (300, vec![Some(7.0)])]);
+
+    let results = run(&db, "select max(cpu) from m group by *").unwrap();
+    assert_eq!(results.len(), 3);
+}
+
+#[test]
+fn test_fill() {
+    let db = test_db(&[("cpu", &[], 60, 1.0), ("cpu", &[], 240, 4.0)]);
+    let results = run(&db, "select max(cpu) from m where time >= 0 and time < 360 group by time(1m) fill(linear)").unwrap();
+    assert_eq!(
+        results[0].rows,
+        vec![
+            (0, vec![None]),
+            (60, vec![Some(1.0)]),
+            (120, vec![Some(2.0)]),
+            (180, vec![Some(3.0)]),
+            (240, vec![Some(4.0)]),
+            (300, vec![None]),
+        ]
+    );
+
+    let results = run(&db, "select count(cpu) from m group by time(1m) fill(0)").unwrap();
+    assert_eq!(results[0].rows.len(), 4);
+    assert_eq!(results[0].rows[1], (120, vec![Some(0.0)]));
+
+    assert!(run(&db, "select max(cpu) from m fill(0)").is_err());
+}
+
+#[test]
+fn test_order_and_limits() {
+    let mut points: Vec<TestPoint> = (0..300).map(|time| ("cpu", &[][..], time * 7, time as f64)).collect();
+    points.push(("cpu", &[("host", "a")], 0, 1.0));
+    let db = test_db(&points);
+
+    let results = run(&db, "select cpu from m order by time desc limit 3 offset 1 slimit 1").unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].tags.is_empty());
+    assert_eq!(
+        results[0].rows,
+        vec![(2086, vec![Some(298.0)]), (2079, vec![Some(297.0)]), (2072, vec![Some(296.0)])]
+    );
+
+    let results = run(&db, "select cpu from m where time >= 60 and time <= 80 order by time desc").unwrap();
+    assert_eq!(
+        results[0].rows,
+        vec![(77, vec![Some(11.0)]), (70, vec![Some(10.0)]), (63, vec![Some(9.0)])]
+    );
+
+    let results = run(&db, "select cpu from m soffset 1").unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].tags["host"], "a");
+
+    let results = run(&db, "select count(cpu) from m group by time(10m) order by time desc limit 2").unwrap();
+    assert_eq!(results[0].rows, vec![(1800, vec![Some(42.0)]), (1200, vec![Some(86.0)])]);
+}
+
+#[test]
+fn test_boolean_where() {
+    let db = test_db(&[
+        ("cpu", &[("host", "a")], 0, 1.0),
+        ("cpu", &[("host", "b")], 0, 2.0),
+        ("cpu", &[("host", "c")], 0, 4.0),
+        ("cpu", &[], 0, 8.0),
+    ]);
+    let sum = |sql| run(&db, sql).unwrap()[0].rows[0].1[0];
+    assert_eq!(sum("select sum(cpu) from m where host = 'a' or host = 'b'"), Some(3.0));
+    assert_eq!(sum("select sum(cpu) from m where host != 'a'"), Some(14.0));
+    assert_eq!(sum("select sum(cpu) from m where host not in ('a', 'c')"), Some(10.0));
+    assert_eq!(
+        sum("select sum(cpu) from m where time >= 0 and not (host = 'a' or host = 'b')"),
+        Some(12.0)
+    );
+
+    assert!(run(&db, "select sum(cpu) from m where host = 'a' or time > 0").is_err());
+    assert!(run(&db, "select sum(cpu) from m where host > 'a'").is_err());
+}
+
+#[test]
+fn test_regex() {
+    let db = test_db(&[
+        ("cpuuser", &[("host", "web-1")], 0, 1.0),
+        ("cpuuser", &[("host", "web-2")], 0, 2.0),
+        ("cpuuser", &[("host", "db-1")], 0, 4.0),
+        ("cpusystem", &[("host", "web-1")], 0, 8.0),
+        ("mem", &[("host", "web-1")], 0, 16.0),
+    ]);
+    let results = run(&db, "select sum(cpuuser) from m where host =~ /^web-\\d+$/").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(3.0)])]);
+    let results = run(&db, "select sum(cpuuser) from m where host !~ /^web/").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(4.0)])]);
+
+    let results = run(&db, "select max(/^cpu/) from m").unwrap();
+    assert_eq!(results[0].columns, vec!["max(cpusystem)", "max(cpuuser)"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(8.0), Some(4.0)])]);
+
+    let results = run(&db, "select /^cpus/, mem from m").unwrap();
+    let names: Vec<_> = results.iter().map(|result| result.columns[0].as_str()).collect();
+    assert_eq!(names, vec!["cpusystem", "mem"]);
+
+    assert!(run(&db, "select /^nothing/ from m").unwrap().is_empty());
+    assert!(run(&db, "select max(/(/) from m").is_err());
+}
+
+#[test]
+fn test_value_conditions() {
+    let db = test_db(&[
+        ("cpu", &[("host", "a")], 0, 95.0),
+        ("cpu", &[("host", "a")], 1, 50.0),
+        ("cpu", &[("host", "b")], 2, 99.0),
+        ("cpu", &[("host", "b")], 3, 15.0),
+    ]);
+    let results = run(&db, "select cpu from m where value > 90 and host = 'a'").unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(95.0)])]);
+
+    let results = run(&db, "select count(cpu), min(cpu) from m where value between 10 and 60").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(2.0), Some(15.0)])]);
+
+    let results = run(&db, "select cpu from m where value < 20 or value >= 99 order by time desc limit 1").unwrap();
+    assert_eq!(results[1].rows, vec![(3, vec![Some(15.0)])]);
+
+    assert!(run(&db, "select cpu from m where value > 90 or host = 'a'").is_err());
+}
+
+#[test]
+fn test_time_literals() {
+    let plan = |sql| {
+        let (_, select) = crate::parser::select::select_parser(sql).unwrap();
+        Plan::at(&select, 1_000 * NANOS_IN_SECOND + 500_000_000)
+    };
+    let range = |sql| {
+        let plan = plan(sql).unwrap();
+        (plan.time_start, plan.time_end)
+    };
+    assert_eq!(range("select x from m where time > now() - 10s"), (991, Timestamp::MAX));
+    assert_eq!(range("select x from m where time >= now() - 10s"), (991, Timestamp::MAX));
+    assert_eq!(range("select x from m where time < now() and time > 5"), (6, 1000));
+    assert_eq!(range("select x from m where time <= now() - 500ms"), (Timestamp::MIN, 1000));
+    assert_eq!(range("select x from m where time between 10 and now()"), (10, 1000));
+    assert_eq!(
+        range("select x from m where time >= '1970-01-01T00:01:00Z' and time < '1970-01-01T00:02:00Z'"),
+        (60, 119)
+    );
+    assert_eq!(range("select x from m where time = 10 + 1ms").0, 11);
+    assert!(plan("select x from m where time != 10").is_err());
+    assert!(plan("select x from m where time > 1 or host = 'a'").is_err());
+
+    let db = test_db(&[("cpu", &[], 60, 1.0), ("cpu", &[], 90, 2.0), ("cpu", &[], 120, 3.0)]);
+    let results = run(&db, "select sum(cpu) from m where time between '1970-01-01T00:01:00Z' and 90").unwrap();
+    assert_eq!(results[0].rows, vec![(60, vec![Some(3.0)])]);
+    let results = run(&db, "select count(cpu) from m where time > now() - 1h").unwrap();
+    assert_eq!(results[0].rows[0].1, vec![Some(0.0)]);
+}
+
+#[test]
+fn test_counters() {
+    let db = test_db(&[
+        ("requests", &[("host", "a")], 0, 100.0),
+        ("requests", &[("host", "a")], 30, 160.0),
+        ("requests", &[("host", "a")], 60, 10.0),
+        ("requests", &[("host", "a")], 90, 70.0),
+        ("requests", &[("host", "b")], 0, 0.0),
+        ("requests", &[("host", "b")], 90, 90.0),
+    ]);
+    let results = run(&db, "select rate(requests), irate(requests) from m where host = 'a'").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(130.0 / 90.0), Some(2.0)])]);
+    let results = run(&db, "select rate(requests) from m").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(130.0 / 90.0 + 1.0)])]);
+    let results = run(&db, "select rate(requests) from m where host = 'a' group by time(1m)").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(2.0)]), (60, vec![Some(2.0)])]);
+
+    let results = run(&db, "select non_negative_derivative(requests, 1m), difference(requests) from m where host = 'a'").unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].columns, vec!["non_negative_derivative(requests, 1m)"]);
+    assert_eq!(results[0].rows, vec![(30, vec![Some(120.0)]), (90, vec![Some(120.0)])]);
+    assert_eq!(
+        results[1].rows,
+        vec![(30, vec![Some(60.0)]), (60, vec![Some(-150.0)]), (90, vec![Some(60.0)])]
+    );
+    let results = run(&db, "select derivative(requests) from m where host = 'b' order by time desc").unwrap();
+    assert_eq!(results[0].rows, vec![(90, vec![Some(1.0)])]);
+
+    let results = run(&db, "select difference(max(requests)) from m group by time(1m)").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![None]), (60, vec![Some(-70.0)])]);
+
+    assert!(run(&db, "select derivative(requests, 1m, 2s) from m").is_err());
+    assert!(run(&db, "select difference(difference(max(requests))) from m").is_err());
+}
+
+#[test]
+fn test_distributions() {
+    let mut points: Vec<TestPoint> = (1..=100).map(|time| ("latency", &[("host", "a")][..], time, time as f64)).collect();
+    points.push(("latency", &[("host", "b")], 1, 1000.0));
+    let db = test_db(&points);
+
+    let results = run(&db, "select percentile(latency, 99), median(latency), spread(latency) from m where host = 'a'").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(99.0), Some(50.5), Some(99.0)])]);
+    let results = run(&db, "select percentile(latency, 100), approx_median(latency) from m").unwrap();
+    assert_eq!(results[0].rows[0].1[0], Some(1000.0));
+    assert!((results[0].rows[0].1[1].unwrap() - 51.0).abs() <= 0.51);
+
+    let results = run(&db, "select histogram(latency, 10, 50) from m").unwrap();
+    assert_eq!(
+        results[0].columns,
+        vec!["histogram(latency, 10, 50){le=10}", "histogram(latency, 10, 50){le=50}", "histogram(latency, 10, 50){le=+Inf}"]
+    );
+    assert_eq!(results[0].rows, vec![(0, vec![Some(10.0), Some(50.0), Some(101.0)])]);
+
+    let results = run(&db, "select stddev(latency) from m where host = 'a' and time <= 3 group by time(2s)").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![None]), (2, vec![Some(0.5f64.sqrt())])]);
+    let results = run(&db, "select difference(histogram(latency, 50)) from m where host = 'a' group by time(50s)").unwrap();
+    assert_eq!(results[0].columns[0], "difference(histogram(latency, 50)){le=50}");
+    assert_eq!(results[0].rows[1], (50, vec![Some(-48.0), Some(1.0)]));
+
+    assert!(run(&db, "select percentile(latency) from m").is_err());
+}
+
+#[test]
+fn test_smoothing() {
+    let db = test_db(&[
+        ("cpu", &[], 0, 1.0),
+        ("cpu", &[], 10, 3.0),
+        ("cpu", &[], 20, 5.0),
+        ("cpu", &[], 70, 7.0),
+        ("cpu", &[], 130, 9.0),
+    ]);
+    let results = run(&db, "select moving_average(cpu, 2), cumulative_sum(cpu) from m limit 2").unwrap();
+    assert_eq!(results[0].columns, vec!["moving_average(cpu, 2)"]);
+    assert_eq!(results[0].rows, vec![(10, vec![Some(2.0)]), (20, vec![Some(4.0)])]);
+    assert_eq!(results[1].rows, vec![(0, vec![Some(1.0)]), (10, vec![Some(4.0)])]);
+
+    let results = run(&db, "select moving_sum(max(cpu), 2), ewma(sum(cpu), 0.5) from m group by time(1m)").unwrap();
+    assert_eq!(
+        results[0].rows,
+        vec![
+            (0, vec![None, Some(9.0)]),
+            (60, vec![Some(12.0), Some(8.0)]),
+            (120, vec![Some(16.0), Some(8.5)]),
+        ]
+    );
+
+    assert!(run(&db, "select moving_average(cpu) from m").is_err());
+}
+
+#[test]
+fn test_arithmetic() {
+    let db = test_db(&[
+        ("used", &[("host", "a")], 0, 3.0),
+        ("used", &[("host", "a")], 60, 6.0),
+        ("used", &[("host", "b")], 0, 1.0),
+        ("total", &[("host", "a")], 0, 12.0),
+        ("total", &[("host", "a")], 30, 12.0),
+        ("total", &[("host", "a")], 60, 0.0),
+        ("bytes", &[], 0, 2e6),
+        ("bytes", &[], 60, 4e6),
+    ]);
+    let results = run(&db, "select bytes * 8 / 1e6 as mbps from net").unwrap();
+    assert_eq!(results[0].name, "net");
+    assert_eq!(results[0].columns, vec!["mbps"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(16.0)]), (60, vec![Some(32.0)])]);
+
+    // Only host a has both, and only at 0 without dividing by zero
+    let results = run(&db, "select used / total * 100 from m").unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].tags["host"], "a");
+    assert_eq!(results[0].columns, vec!["used / total * 100"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(25.0)])]);
+
+    let results = run(&db, "select sum(used) / count(used) as mean, max(total) - min(total), max(used) as top from m").unwrap();
+    assert_eq!(results[0].columns, vec!["mean", "max(total) - min(total)", "top"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(10.0 / 3.0), Some(12.0), Some(6.0)])]);
+
+    let results = run(&db, "select -max(used) % 4, count(bytes) * 2 from m group by time(1m)").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(-3.0), Some(2.0)]), (60, vec![Some(-2.0), Some(2.0)])]);
+
+    let results = run(&db, "select histogram(used, 2) as h from m").unwrap();
+    assert_eq!(results[0].columns, vec!["h{le=2}", "h{le=+Inf}"]);
+
+    assert!(run(&db, "select max(used) / total from m").is_err());
+    assert!(run(&db, "select histogram(used, 2) * 2 from m").is_err());
+    assert!(run(&db, "select 1 + 2 from m").is_err());
+
+    // Operands are joined as they are read, so LIMIT stops the scans
+    let points: Vec<TestPoint> = (0..100)
+        .flat_map(|minute| {
+            let time = minute * 60;
+            [("a", &[][..], time, 1.0), ("b", &[][..], time + 30, 2.0), ("b", &[][..], time, 3.0)]
+        })
+        .collect();
+    let db = test_db(&points);
+    let results = run(&db, "select a + b from m order by time desc limit 2").unwrap();
+    assert_eq!(results[0].rows, vec![(5940, vec![Some(4.0)]), (5880, vec![Some(4.0)])]);
+    let counting = crate::db::counting::CountingDB::new(&db);
+    let (_, select) = crate::parser::select::select_parser("select a + b from m limit 2").unwrap();
+    let mut results = Vec::new();
+    execute(&counting, &select, &mut results).unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(4.0)]), (60, vec![Some(4.0)])]);
+    assert!(counting.keys_read() < 20, "read {} keys", counting.keys_read());
+}
+
+#[test]
+fn test_top() {
+    let mut points: Vec<TestPoint> = Vec::new();
+    for (host, values) in [("a", [10.0, 50.0]), ("b", [70.0, 20.0]), ("c", [30.0, 40.0]), ("d", [5.0, 5.0])] {
+        points.push(("cpu", &[], 0, 0.0));
+        let tags: &[(&str, &str)] = match host {
+            "a" => &[("host", "a")],
+            "b" => &[("host", "b")],
+            "c" => &[("host", "c")],
+            _ => &[("host", "d"), ("dc", "x")],
+        };
+        points.push(("cpu", tags, 0, values[0]));
+        points.push(("cpu", tags, 60, values[1]));
+    }
+    let db = test_db(&points);
+    let hosts = |results: &[output::ResultSeries]| {
+        results
+            .iter()
+            .map(|result| result.tags.get("host").cloned().unwrap_or_default())
+            .collect::<Vec<_>>()
+    };
+
+    let results = run(&db, "select top(cpu, host, 2) from m").unwrap();
+    assert_eq!(hosts(&results), vec!["b", "a"]);
+    assert_eq!(results[0].columns, vec!["top(cpu, host, 2)"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(70.0)])]);
+
+    let results = run(&db, "select bottom(mean(cpu), host, 3) as calm from m where time >= 0").unwrap();
+    assert_eq!(hosts(&results), vec!["d", "a", "c"]);
+    assert_eq!(results[1].columns, vec!["calm"]);
+    assert_eq!(results[1].rows, vec![(0, vec![Some(30.0)])]);
+
+    let results = run(&db, "select top(cpu, host, 1) from m group by time(1m)").unwrap();
+    assert_eq!(hosts(&results), vec!["b"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(70.0)]), (60, vec![Some(20.0)])]);
+
+    // Without tags every series is ranked on its own
+    let results = run(&db, "select bottom(cpu, 2) from m").unwrap();
+    assert_eq!(results[0].tags.len(), 0);
+    assert_eq!(results[1].tags["dc"], "x");
+
+    assert!(run(&db, "select top(cpu, host) from m").is_err());
+    assert!(run(&db, "select top(cpu, host, 2), max(cpu) from m").is_err());
+    assert!(run(&db, "select top(cpu, host, 2) from m group by dc").is_err());
+}
+
+#[test]
+fn test_subquery() {
+    let db = test_db(&[
+        ("cpu", &[("host", "a")], 0, 10.0),
+        ("cpu", &[("host", "a")], 30, 20.0),
+        ("cpu", &[("host", "a")], 60, 50.0),
+        ("cpu", &[("host", "b")], 0, 40.0),
+        ("cpu", &[("host", "b")], 3600, 1.0),
+    ]);
+    let sql = "select max(avg_cpu) from (select avg(cpu) as avg_cpu from m group by time(1m), host) group by time(1h)";
+    let results = run(&db, sql).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].name, "m");
+    assert_eq!(results[0].columns, vec!["max(avg_cpu)"]);
+    assert_eq!(results[0].rows, vec![(0, vec![Some(50.0)]), (3600, vec![Some(1.0)])]);
+
+    let results = run(&db, "select avg_cpu from (select avg(cpu) as avg_cpu from m group by host) where host = 'a'").unwrap();
+    assert_eq!(results[0].tags["host"], "a");
+    assert_eq!(results[0].rows, vec![(0, vec![Some(80.0 / 3.0)])]);
+
+    let results = run(&db, "select count(\"max(cpu)\") from (select max(cpu) from (select cpu from m) group by host)").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(2.0)])]);
+
+    // The outer range is passed down, the last window whole
+    let sql = "select max(x) from (select avg(cpu) as x from m group by time(1h), host) where host = 'a' and time <= 0";
+    assert_eq!(run(&db, sql).unwrap()[0].rows, vec![(0, vec![Some(80.0 / 3.0)])]);
+    // but not to a transform, which needs the points before it
+    let sql = "select x from (select difference(cpu) as x from m where host = 'a') where time >= 60";
+    assert_eq!(run(&db, sql).unwrap()[0].rows, vec![(60, vec![Some(30.0)])]);
+
+    assert!(run(&db, "select x from (select nothing(cpu) from m)").is_err());
+
+    let points: Vec<TestPoint> = (0..100).map(|minute| ("cpu", &[][..], minute * 60, 1.0)).collect();
+    let db = test_db(&points);
+    let counting = crate::db::counting::CountingDB::new(&db);
+    let (_, select) = crate::parser::select::select_parser("select count(x) from (select cpu as x from m) where time >= 5880").unwrap();
+    let mut results = Vec::new();
+    execute(&counting, &select, &mut results).unwrap();
+    assert_eq!(results[0].rows, vec![(5880, vec![Some(2.0)])]);
+    assert!(counting.keys_read() < 10, "read {} keys", counting.keys_read());
+}
+
+#[test]
+fn test_time_shift() {
+    let week = 7 * 86400;
+    let db = test_db(&[
+        ("cpu", &[("host", "a")], 0, 1.0),
+        ("cpu", &[("host", "a")], 60, 2.0),
+        ("cpu", &[("host", "a")], week, 4.0),
+        ("cpu", &[("host", "a")], week + 60, 8.0),
+    ]);
+    let this_week = format!("where time >= {} and time < {}", week, 2 * week);
+    let results = run(&db, &format!("select cpu from m {} offset 7d", this_week)).unwrap();
+    assert_eq!(results[0].rows, vec![(week, vec![Some(1.0)]), (week + 60, vec![Some(2.0)])]);
+    let results = run(&db, &format!("select cpu from m {} order by time desc offset 1w limit 1", this_week)).unwrap();
+    assert_eq!(results[0].rows, vec![(week + 60, vec![Some(2.0)])]);
+
+    let sql = format!("select cpu - time_shift(cpu, 1w) as growth from m {}", this_week);
+    let results = run(&db, &sql).unwrap();
+    assert_eq!(results[0].columns, vec!["growth"]);
+    assert_eq!(results[0].rows, vec![(week, vec![Some(3.0)]), (week + 60, vec![Some(6.0)])]);
+
+    let sql = format!("select sum(cpu), time_shift(sum(cpu), 1w) from m {} group by time(1d) fill(none)", this_week);
+    let results = run(&db, &sql).unwrap();
+    assert_eq!(results[0].columns, vec!["sum(cpu)", "time_shift(sum(cpu), 1w)"]);
+    assert_eq!(results[0].rows, vec![(week, vec![Some(12.0), Some(3.0)])]);
+
+    assert!(run(&db, "select time_shift(cpu) from m").is_err());
+    let huge = "time_shift(time_shift(cpu, 10000000000000w), 10000000000000w)";
+    assert!(run(&db, &format!("select {} from m", huge)).is_err());
+    let huge = "time_shift(time_shift(max(cpu), 10000000000000w), 10000000000000w)";
+    assert!(run(&db, &format!("select {} from m", huge)).is_err());
+}
+
+#[test]
+fn test_insert_select() {
+    let db = test_db(&[
+        ("cpu", &[("host", "a")], 0, 1.0),
+        ("cpu", &[("host", "a")], 1800, 3.0),
+        ("cpu", &[("host", "b")], 0, 5.0),
+    ]);
+    let insert = |sql| {
+        let (_, select) = crate::parser::select::select_parser(sql).unwrap();
+        let mut writer = output::Writer::new(&db, "cpu_1h");
+        execute(&db, &select, &mut writer).unwrap();
+        writer.finish().unwrap()
+    };
+    assert_eq!(insert("select mean(cpu) from m group by time(1h), host"), 2);
+    let results = run(&db, "select cpu_1h from m where host = 'a'").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(2.0)])]);
+
+    assert_eq!(insert("select min(cpu), max(cpu) from m group by time(1h)"), 2);
+    let results = run(&db, "select \"cpu_1h.min(cpu)\", \"cpu_1h.max(cpu)\" from m").unwrap();
+    assert_eq!(results[0].rows, vec![(0, vec![Some(1.0)])]);
+    assert_eq!(results[1].rows, vec![(0, vec![Some(5.0)])]);
+
+    let insert = |sql| {
+        let (_, select) = crate::parser::select::select_parser(sql).unwrap();
+        let mut writer = output::Writer::new(&db, "copy");
+        execute(&db, &select, &mut writer).and_then(|_| writer.finish())
+    };
+    assert_eq!(insert("select cpu from m").unwrap(), 3);
+    assert!(insert("select cpu, cpu_1h from m").is_err());
+    assert!(insert("select /^cpu/ from m").is_err());
+    let results = run(&db, "select copy from m").unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].rows.len(), 2);
+}
@@ -0,0 +1,148 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use crate::db::datapoint::Datapoint;
+use crate::db::{Timestamp, DB};
+
+// Points a Writer keeps before writing them in one batch
+const WRITE_BATCH_SIZE: usize = 10000;
+
+/// Receives query results as they are produced: a header for each result
+/// series followed by its rows.
+pub trait Sink {
+    fn begin_series(&mut self, name: &str, tags: &HashMap<String, String>, columns: &[String]) -> Result<()>;
+    fn row(&mut self, time: Timestamp, values: &[Option<f64>]) -> Result<()>;
+}
+
+/// Prints results as tab separated text.
+pub struct Printer;
+
+impl Sink for Printer {
+    fn begin_series(&mut self, name: &str, tags: &HashMap<String, String>, columns: &[String]) -> Result<()> {
+        println!("{}", Datapoint::key_string(name, tags));
+        println!("time\t{}", columns.join("\t"));
+        Ok(())
+    }
+
+    fn row(&mut self, time: Timestamp, values: &[Option<f64>]) -> Result<()> {
+        let mut line = time.to_string();
+        for value in values {
+            match value {
+                Some(value) => line.push_str(&format!("\t{}", value)),
+                None => line.push_str("\tnull"),
+            }
+        }
+        println!("{}", line);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSeries {
+    pub name: String,
+    pub tags: HashMap<String, String>,
+    pub columns: Vec<String>,
+    pub rows: Vec<(Timestamp, Vec<Option<f64>>)>,
+}
+
+// Collects the results in memory
+impl Sink for Vec<ResultSeries> {
+    fn begin_series(&mut self, name: &str, tags: &HashMap<String, String>, columns: &[String]) -> Result<()> {
+        self.push(ResultSeries {
+            name: name.to_owned(),
+            tags: tags.clone(),
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn row(&mut self, time: Timestamp, values: &[Option<f64>]) -> Result<()> {
+        if let Some(series) = self.last_mut() {
+            series.rows.push((time, values.to_vec()));
+        }
+        Ok(())
+    }
+}
+
+/// Writes results back into a database as points, for INSERT ... SELECT.
+/// With a single column the points go to the metric `table`, otherwise each
+/// column to `table.column`. Tags are those of the result series, and nulls
+/// are left out. Every result series must have the same name and columns, as
+/// series of different metrics or fields would be written over each other.
+pub struct Writer<'a, D: DB> {
+    db: &'a D,
+    table: String,
+    // The name and columns of the first result series
+    source: Option<(String, Vec<String>)>,
+    // The metric of each column of the current series, and its tags
+    metrics: Vec<String>,
+    tags: HashMap<String, String>,
+    pending: Vec<Datapoint>,
+    written: usize,
+}
+
+impl<'a, D: DB> Writer<'a, D> {
+    pub fn new(db: &'a D, table: &str) -> Self {
+        Writer {
+            db,
+            table: table.to_owned(),
+            source: None,
+            metrics: Vec::new(),
+            tags: HashMap::new(),
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    // Writes what's left, returning the number of points written
+    pub fn finish(mut self) -> Result<usize> {
+        self.flush()?;
+        Ok(self.written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.written += self.pending.len();
+        self.db.put_datapoints(std::mem::take(&mut self.pending))
+    }
+}
+
+impl<D: DB> Sink for Writer<'_, D> {
+    fn begin_series(&mut self, name: &str, tags: &HashMap<String, String>, columns: &[String]) -> Result<()> {
+        match &self.source {
+            Some((first_name, first_columns)) if first_name != name || first_columns != columns => bail!(
+                "cannot write both {} ({}) and {} ({}) to `{}`, select one field of one metric at a time",
+                first_name,
+                first_columns.join(", "),
+                name,
+                columns.join(", "),
+                self.table
+            ),
+            Some(_) => {}
+            None => self.source = Some((name.to_owned(), columns.to_vec())),
+        }
+        self.metrics = match columns {
+            [_] => vec![self.table.clone()],
+            _ => columns.iter().map(|column| format!("{}.{}", self.table, column)).collect(),
+        };
+        self.tags = tags.clone();
+        Ok(())
+    }
+
+    fn row(&mut self, time: Timestamp, values: &[Option<f64>]) -> Result<()> {
+        for (metric, value) in self.metrics.iter().zip(values) {
+            if let Some(value) = value {
+                self.pending.push(Datapoint {
+                    metric: metric.clone(),
+                    tags: self.tags.clone(),
+                    time,
+                    value: *value,
+                });
+            }
+        }
+        if self.pending.len() >= WRITE_BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
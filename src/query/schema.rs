@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::filter::Filter;
+use crate::db::series::Series;
+use crate::db::DB;
+
+// Series of `metric`, or of every metric, whose tags pass the filter. Only
+// the series keys are scanned, in key order, stopping after `limit` series.
+pub fn series<'a, D: DB>(
+    db: &'a D,
+    metric: Option<&str>,
+    filter: &'a Filter,
+    limit: Option<usize>,
+) -> impl Iterator<Item = Series> + 'a {
+    db.list_series(metric)
+        .filter(move |series| filter.matches(&series.tags))
+        .take(limit.unwrap_or(usize::MAX))
+}
+
+// Names of the metrics with a matching series. With a limit the scan stops
+// at that many, which are the first in key order.
+pub fn metrics<D: DB>(db: &D, filter: &Filter, limit: Option<usize>) -> BTreeSet<String> {
+    let mut output = BTreeSet::new();
+    for series in series(db, None, filter, None) {
+        if output.len() >= limit.unwrap_or(usize::MAX) {
+            break;
+        }
+        output.insert(series.metric);
+    }
+    output
+}
+
+// The tag keys of the matching series, by metric, stopping at `limit` of them
+pub fn tag_keys<D: DB>(
+    db: &D,
+    metric: Option<&str>,
+    filter: &Filter,
+    limit: Option<usize>,
+) -> BTreeMap<String, BTreeSet<String>> {
+    by_metric(series(db, metric, filter, None).map(|series| (series.metric, series.tags.into_keys().collect())), limit)
+}
+
+// The values of tag `key` in the matching series, by metric, stopping at
+// `limit` of them. Series without the tag are left out.
+pub fn tag_values<D: DB>(
+    db: &D,
+    metric: Option<&str>,
+    key: &str,
+    filter: &Filter,
+    limit: Option<usize>,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let values = series(db, metric, filter, None)
+        .filter_map(|mut series| Some((series.metric, vec![series.tags.remove(key)?])));
+    by_metric(values, limit)
+}
+
+// Collects distinct names by metric until there are `limit` of them
+fn by_metric(
+    names: impl Iterator<Item = (String, Vec<String>)>,
+    limit: Option<usize>,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut output = BTreeMap::<String, BTreeSet<String>>::new();
+    let mut count = 0;
+    for (metric, names) in names {
+        for name in names {
+            if count >= limit {
+                return output;
+            }
+            if output.entry(metric.clone()).or_default().insert(name) {
+                count += 1;
+            }
+        }
+    }
+    output
+}
+
+#[test]
+fn test_schema() {
+    use crate::parser::select::where_parser;
+    use std::collections::HashMap;
+
+    let db = super::test_db(&[
+        ("cpu", &[("host", "a"), ("dc", "eu")], 0, 1.0),
+        ("cpu", &[("host", "b"), ("dc", "us")], 0, 1.0),
+        ("cpu2", &[("host", "a")], 0, 1.0),
+        ("mem", &[("host", "b"), ("rack", "r1")], 0, 1.0),
+    ]);
+    let filter = |sql| Filter::new(&where_parser(sql).unwrap().1).unwrap();
+    let everything = Filter::new(&[]).unwrap();
+    let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<BTreeSet<_>>();
+
+    assert_eq!(metrics(&db, &everything, None), names(&["cpu", "cpu2", "mem"]));
+    assert_eq!(metrics(&db, &filter("where host = 'a'"), None).len(), 2);
+    assert_eq!(metrics(&db, &everything, Some(2)), names(&["cpu", "cpu2"]));
+
+    let keys = tag_keys(&db, None, &filter("where host = 'b'"), None);
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys["mem"], names(&["host", "rack"]));
+    assert_eq!(tag_keys(&db, Some("cpu2"), &everything, None)["cpu2"].len(), 1);
+    let keys = tag_keys(&db, None, &everything, Some(3));
+    assert_eq!(keys.values().map(BTreeSet::len).sum::<usize>(), 3);
+    assert_eq!(keys["cpu"], names(&["dc", "host"]));
+
+    let values = tag_values(&db, Some("cpu"), "dc", &everything, None);
+    assert_eq!(values, BTreeMap::from([("cpu".to_owned(), names(&["eu", "us"]))]));
+    assert!(tag_values(&db, None, "rack", &filter("where host = 'a'"), None).is_empty());
+    let values = tag_values(&db, None, "host", &everything, Some(2));
+    assert_eq!(values, BTreeMap::from([("cpu".to_owned(), names(&["a", "b"]))]));
+
+    let found: Vec<_> = series(&db, Some("cpu"), &filter("where dc =~ /u/"), None).map(|series| series.tags).collect();
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0], HashMap::from([("host".to_owned(), "a".to_owned()), ("dc".to_owned(), "eu".to_owned())]));
+    let found: Vec<_> = series(&db, None, &filter("where host = 'b'"), Some(1)).map(|series| series.metric).collect();
+    assert_eq!(found, vec!["cpu"]);
+    assert_eq!(series(&db, None, &everything, Some(0)).count(), 0);
+    assert_eq!(series(&db, None, &everything, Some(10)).count(), 4);
+}
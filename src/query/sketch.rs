@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+// Quantiles are within 1% of the exact value
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// A DDSketch: counts of values in buckets whose bounds grow geometrically,
+/// so that any quantile can be estimated within a relative error while the
+/// number of buckets only grows with the log of the range of values. Sketches
+/// of different series can be merged.
+#[derive(Debug, Clone, Default)]
+pub struct Sketch {
+    positive: BTreeMap<i32, u64>,
+    // Buckets of the magnitude of negative values
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl Sketch {
+    pub fn push(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        self.count += 1;
+        if value > f64::MIN_POSITIVE {
+            *self.positive.entry(bucket(value)).or_default() += 1;
+        } else if value < -f64::MIN_POSITIVE {
+            *self.negative.entry(bucket(-value)).or_default() += 1;
+        } else {
+            self.zero += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (index, count) in &other.positive {
+            *self.positive.entry(*index).or_default() += count;
+        }
+        for (index, count) in &other.negative {
+            *self.negative.entry(*index).or_default() += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+    }
+
+    // The value of nearest rank for `quantile` between 0 and 1
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen >= rank {
+                return Some(-bucket_value(*index));
+            }
+        }
+        seen += self.zero;
+        if seen >= rank {
+            return Some(0.0);
+        }
+        for (index, count) in &self.positive {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_value(*index));
+            }
+        }
+        None
+    }
+}
+
+fn gamma() -> f64 {
+    (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+// Bucket i holds the values in (gamma^(i-1), gamma^i]
+fn bucket(value: f64) -> i32 {
+    (value.ln() / gamma().ln()).ceil() as i32
+}
+
+// The value within the relative accuracy of everything in a bucket
+fn bucket_value(index: i32) -> f64 {
+    2.0 * gamma().powi(index) / (gamma() + 1.0)
+}
+
+#[test]
+fn test_sketch() {
+    let mut sketch = Sketch::default();
+    assert_eq!(sketch.quantile(0.5), None);
+    for value in 1..=1000 {
+        sketch.push(value as f64);
+    }
+    let close = |estimate: Option<f64>, exact: f64| (estimate.unwrap() - exact).abs() <= exact * RELATIVE_ACCURACY;
+    assert!(close(sketch.quantile(0.5), 500.0));
+    assert!(close(sketch.quantile(0.99), 990.0));
+    assert!(close(sketch.quantile(0.0), 1.0));
+    assert!(close(sketch.quantile(1.0), 1000.0));
+
+    let mut other = Sketch::default();
+    for value in [-5.0, 0.0, -2.0] {
+        other.push(value);
+    }
+    assert!(close(other.quantile(0.0).map(f64::abs), 5.0));
+    assert_eq!(other.quantile(1.0), Some(0.0));
+    sketch.merge(&other);
+    assert!(close(sketch.quantile(0.5), 499.0));
+    assert!(close(sketch.quantile(0.001).map(f64::abs), 2.0));
+}
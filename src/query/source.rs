@@ -0,0 +1,93 @@
+use anyhow::Result;
+use std::cmp;
+
+use super::output::ResultSeries;
+use crate::db::datapoint::Datapoint;
+use crate::db::series::Series;
+use crate::db::{Timestamp, DB};
+use crate::parser::select::Order;
+
+pub type Points<'a> = Box<dyn Iterator<Item = Result<(Timestamp, f64)>> + 'a>;
+
+/// Where a SELECT reads its series from: a database, or the results of a
+/// subquery.
+pub trait Source {
+    // The series of `metric`, or of every metric, in key order
+    fn series_of(&self, metric: Option<&str>) -> Box<dyn Iterator<Item = Series> + '_>;
+    // The points of a series from `start` to `end`, both included, in `order`
+    fn points(&self, series: &Series, start: Timestamp, end: Timestamp, order: Order) -> Result<Points<'_>>;
+}
+
+impl<D: DB> Source for D {
+    fn series_of(&self, metric: Option<&str>) -> Box<dyn Iterator<Item = Series> + '_> {
+        self.list_series(metric)
+    }
+
+    fn points(&self, series: &Series, start: Timestamp, end: Timestamp, order: Order) -> Result<Points<'_>> {
+        Ok(match order {
+            Order::Asc => Box::new(self.scan_series(series, &start, &end)),
+            Order::Desc => Box::new(self.scan_series_rev(series, &start, &end)),
+        })
+    }
+}
+
+/// The results of a subquery read as series: each column of a result series
+/// is a series of the metric named after the column, with the tags of the
+/// result series and its non-null values. Ids are indexes into `series`.
+pub struct Results {
+    series: Vec<(Series, Vec<(Timestamp, f64)>)>,
+}
+
+impl Results {
+    pub fn new(results: Vec<ResultSeries>) -> Results {
+        let mut series = Vec::new();
+        for result in results {
+            for (i, column) in result.columns.iter().enumerate() {
+                let mut points: Vec<_> = result
+                    .rows
+                    .iter()
+                    .filter_map(|(time, values)| Some((*time, values[i]?)))
+                    .collect();
+                points.sort_by_key(|(time, _)| *time);
+                let series_of_column = Series {
+                    id: 0,
+                    metric: column.clone(),
+                    tags: result.tags.clone(),
+                };
+                series.push((series_of_column, points));
+            }
+        }
+        // In key order, as a database lists them
+        series.sort_by_cached_key(|(series, _)| Datapoint::key_string(&series.metric, &series.tags));
+        for (id, (series, _)) in series.iter_mut().enumerate() {
+            series.id = id as u64;
+        }
+        Results { series }
+    }
+}
+
+impl Source for Results {
+    fn series_of(&self, metric: Option<&str>) -> Box<dyn Iterator<Item = Series> + '_> {
+        let metric = metric.map(str::to_owned);
+        Box::new(
+            self.series
+                .iter()
+                .filter(move |(series, _)| metric.as_ref().map_or(true, |metric| *metric == series.metric))
+                .map(|(series, _)| series.clone()),
+        )
+    }
+
+    fn points(&self, series: &Series, start: Timestamp, end: Timestamp, order: Order) -> Result<Points<'_>> {
+        let points = match self.series.get(series.id as usize) {
+            Some((_, points)) => points,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        let from = points.partition_point(|(time, _)| *time < start);
+        let to = cmp::max(from, points.partition_point(|(time, _)| *time <= end));
+        let points = points[from..to].iter().map(|point| Ok(*point));
+        Ok(match order {
+            Order::Asc => Box::new(points),
+            Order::Desc => Box::new(points.rev()),
+        })
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{bail, Result};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap};
+
+use super::aggregate::Accumulator;
+use super::output::Sink;
+use super::source::Source;
+use super::{
+    execute_aggregates, group_key, matching_series, resolve_aggregate, scan_points, scanned, window, GroupKey,
+    Plan,
+};
+use crate::parser::select::{Expr, Fill, GroupBy, Order, Select, SelectField, TagGrouping};
+
+// top(x, tags..., n) or bottom(...)
+pub fn is_selector(expr: &Expr) -> bool {
+    matches!(expr, Expr::Call { function, .. } if function == "top" || function == "bottom")
+}
+
+/// Ranks the groups of series with the same `tags`, or every series without
+/// tags, by an aggregate of x over the whole time range, and reports the `n`
+/// highest for top or lowest for bottom, best first. The aggregate is max for
+/// top and min for bottom unless x is a call, as in top(mean(cpu), host, 5).
+pub fn execute_top<S: Source>(db: &S, select: &Select, plan: &Plan, sink: &mut dyn Sink) -> Result<()> {
+    let field = match select.fields.as_slice() {
+        [field] if is_selector(&field.expr) => field,
+        _ => bail!("top and bottom must be the only field"),
+    };
+    let (function, args) = match &field.expr {
+        Expr::Call { function, args } => (function.as_str(), args),
+        _ => bail!("expected top or bottom, found {}", field.expr),
+    };
+    if select.group_by.tags != TagGrouping::None {
+        bail!("{} groups by its own tags", function);
+    }
+    if select.fill != Fill::None && select.group_by.time.is_none() {
+        bail!("FILL needs GROUP BY time");
+    }
+
+    let (count, ranked, tags) = match args.as_slice() {
+        [ranked, tags @ .., Expr::Number(count)] if *count >= 1.0 && count.fract() == 0.0 => {
+            (*count as usize, ranked, tags)
+        }
+        _ => bail!("{} takes a metric or an aggregate, tags and a count", function),
+    };
+    let grouping = if tags.is_empty() {
+        TagGrouping::All
+    } else {
+        TagGrouping::Keys(
+            tags.iter()
+                .map(|tag| match tag {
+                    Expr::Field(tag) => Ok(tag.clone()),
+                    _ => bail!("expected a tag, found {}", tag),
+                })
+                .collect::<Result<_>>()?,
+        )
+    };
+    let aggregate = match ranked {
+        Expr::Field(_) => Expr::Call {
+            function: if function == "top" { "max" } else { "min" }.to_owned(),
+            args: vec![ranked.clone()],
+        },
+        _ => ranked.clone(),
+    };
+    let columns = resolve_aggregate(&aggregate)?;
+    let column = match columns.as_slice() {
+        [column] if column.transform.is_none() => column,
+        _ => bail!("{} cannot rank by {}", function, ranked),
+    };
+
+    // Series missing one of the tags aren't candidates
+    let mut candidates = BTreeMap::<GroupKey, Accumulator>::new();
+    plan.stage("series lookup");
+    for series in matching_series(db, column.metric, plan) {
+        let key = group_key(&series.tags, &grouping);
+        if key.len() < tags.len() {
+            continue;
+        }
+        let points = scanned(plan, scan_points(db, &series, plan, column.shift, Order::Asc)?, "aggregation");
+        for (_, accs) in window::fold_windows(points, &[column.aggregate], None, plan.row_time())? {
+            for acc in accs {
+                match candidates.get_mut(&key) {
+                    Some(merged) => merged.merge(&acc),
+                    None => {
+                        candidates.insert(key.clone(), acc);
+                    }
+                }
+            }
+        }
+    }
+
+    // The worst of the best so far is on top, to be pushed out by a better one
+    plan.stage("aggregation");
+    let mut best = BinaryHeap::with_capacity(count + 1);
+    for (key, acc) in candidates {
+        if let Some(value) = acc.finish() {
+            let score = if function == "top" { value } else { -value };
+            best.push(Reverse(Ranked { score, key }));
+            if best.len() > count {
+                best.pop();
+            }
+        }
+    }
+    let keys: Vec<GroupKey> = best.into_sorted_vec().into_iter().map(|Reverse(ranked)| ranked.key).collect();
+
+    let ranking = Select {
+        fields: vec![SelectField {
+            expr: aggregate.clone(),
+            alias: Some(field.alias.clone().unwrap_or_else(|| field.expr.to_string())),
+        }],
+        group_by: GroupBy {
+            time: select.group_by.time,
+            tags: grouping,
+        },
+        ..select.clone()
+    };
+    execute_aggregates(db, &ranking, &ranking.fields, plan, Some(&keys), sink)
+}
+
+// A candidate by how good it is, ties going to the first in key order
+struct Ranked {
+    score: f64,
+    key: GroupKey,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.key.cmp(&self.key))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
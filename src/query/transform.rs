@@ -0,0 +1,209 @@
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+
+use crate::db::Timestamp;
+use crate::parser::select::Expr;
+
+/// A function giving a value for each point from the points before it,
+/// rather than one value for many points as aggregates do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    // Change per `unit` seconds since the previous point
+    Derivative { unit: Timestamp },
+    // Same, but a counter reset gives no value instead of a negative one
+    NonNegativeDerivative { unit: Timestamp },
+    Difference,
+    // Over the last `points` values, none until there are that many
+    MovingAverage { points: usize },
+    MovingSum { points: usize },
+    CumulativeSum,
+    // Each value weighs `alpha` against the average so far
+    Ewma { alpha: f64 },
+}
+
+impl Transform {
+    // The transform called and the expression it applies to, or None if
+    // `function` isn't a transform
+    pub fn from_call<'a>(function: &str, args: &'a [Expr]) -> Result<Option<(Transform, &'a Expr)>> {
+        if !matches!(
+            function,
+            "derivative"
+                | "non_negative_derivative"
+                | "difference"
+                | "moving_average"
+                | "moving_sum"
+                | "cumulative_sum"
+                | "ewma"
+        ) {
+            return Ok(None);
+        }
+        let unit = |args: &[Expr]| match args {
+            [] => Ok(1),
+            [Expr::Duration(unit)] if *unit > 0 => Ok(*unit),
+            _ => bail!("{} takes a metric and an optional duration", function),
+        };
+        let points = |args: &[Expr]| match args {
+            [Expr::Number(points)] if *points >= 1.0 && points.fract() == 0.0 => Ok(*points as usize),
+            _ => bail!("{} takes a metric and a number of points", function),
+        };
+        let (arg, rest) = match args.split_first() {
+            Some(split) => split,
+            None => bail!("{} needs an argument", function),
+        };
+        let transform = match function {
+            "derivative" => Transform::Derivative { unit: unit(rest)? },
+            "non_negative_derivative" => Transform::NonNegativeDerivative { unit: unit(rest)? },
+            "moving_average" => Transform::MovingAverage { points: points(rest)? },
+            "moving_sum" => Transform::MovingSum { points: points(rest)? },
+            "ewma" => match rest {
+                [Expr::Number(alpha)] if *alpha > 0.0 && *alpha <= 1.0 => Transform::Ewma { alpha: *alpha },
+                _ => bail!("ewma takes a metric and a weight above 0 and up to 1"),
+            },
+            _ if !rest.is_empty() => bail!("{} takes a single argument", function),
+            "difference" => Transform::Difference,
+            _ => Transform::CumulativeSum,
+        };
+        Ok(Some((transform, arg)))
+    }
+}
+
+/// Running state of a transform over time ordered points.
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    transform: Transform,
+    previous: Option<(Timestamp, f64)>,
+    // The last values of a moving window, and their sum
+    window: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Transformer {
+    pub fn new(transform: Transform) -> Self {
+        Transformer {
+            transform,
+            previous: None,
+            window: VecDeque::new(),
+            sum: 0.0,
+        }
+    }
+
+    // The value for a point, if it has one
+    pub fn push(&mut self, time: Timestamp, value: f64) -> Option<f64> {
+        match self.transform {
+            Transform::MovingAverage { points } => self.slide(value, points).map(|sum| sum / points as f64),
+            Transform::MovingSum { points } => self.slide(value, points),
+            Transform::CumulativeSum => {
+                self.sum += value;
+                Some(self.sum)
+            }
+            Transform::Ewma { alpha } => {
+                let average = match self.previous {
+                    Some((_, average)) => alpha * value + (1.0 - alpha) * average,
+                    None => value,
+                };
+                self.previous = Some((time, average));
+                Some(average)
+            }
+            _ => self.change(time, value),
+        }
+    }
+
+    // The derivatives and difference, none for the first point
+    fn change(&mut self, time: Timestamp, value: f64) -> Option<f64> {
+        let (previous_time, previous_value) = match self.previous.replace((time, value)) {
+            Some(previous) if previous.0 < time => previous,
+            _ => return None,
+        };
+        let elapsed = (time - previous_time) as f64;
+        match self.transform {
+            Transform::Derivative { unit } => Some((value - previous_value) / elapsed * unit as f64),
+            Transform::NonNegativeDerivative { unit } => {
+                if value < previous_value {
+                    None
+                } else {
+                    Some((value - previous_value) / elapsed * unit as f64)
+                }
+            }
+            Transform::Difference => Some(value - previous_value),
+            _ => None,
+        }
+    }
+
+    // Sum of the last `points` values once there are that many
+    fn slide(&mut self, value: f64, points: usize) -> Option<f64> {
+        self.window.push_back(value);
+        self.sum += value;
+        if self.window.len() > points {
+            self.sum -= self.window.pop_front().unwrap_or_default();
+        }
+        (self.window.len() == points).then_some(self.sum)
+    }
+}
+
+#[test]
+fn test_transformer() {
+    let points = [(0, 10.0), (10, 30.0), (30, 5.0), (40, 25.0)];
+    let apply = |transform| {
+        let mut transformer = Transformer::new(transform);
+        points
+            .iter()
+            .map(|(time, value)| transformer.push(*time, *value))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        apply(Transform::Derivative { unit: 1 }),
+        vec![None, Some(2.0), Some(-1.25), Some(2.0)]
+    );
+    assert_eq!(
+        apply(Transform::NonNegativeDerivative { unit: 60 }),
+        vec![None, Some(120.0), None, Some(120.0)]
+    );
+    assert_eq!(apply(Transform::Difference), vec![None, Some(20.0), Some(-25.0), Some(20.0)]);
+
+    let args = [Expr::Field("x".to_owned()), Expr::Duration(60)];
+    assert_eq!(
+        Transform::from_call("derivative", &args).unwrap(),
+        Some((Transform::Derivative { unit: 60 }, &args[0]))
+    );
+    assert_eq!(Transform::from_call("max", &args[..1]).unwrap(), None);
+    assert!(Transform::from_call("difference", &args).is_err());
+    assert!(Transform::from_call("derivative", &[args[0].clone(), Expr::Duration(0)]).is_err());
+
+    let args = [Expr::Field("x".to_owned()), Expr::Number(3.0)];
+    assert_eq!(
+        Transform::from_call("moving_average", &args).unwrap(),
+        Some((Transform::MovingAverage { points: 3 }, &args[0]))
+    );
+    assert!(Transform::from_call("moving_sum", &[args[0].clone(), Expr::Number(1.5)]).is_err());
+    assert!(Transform::from_call("cumulative_sum", &args).is_err());
+    assert!(Transform::from_call("ewma", &[args[0].clone(), Expr::Number(2.0)]).is_err());
+}
+
+#[test]
+fn test_smoothing() {
+    let values = [1.0, 3.0, 5.0, 7.0, 4.0];
+    let apply = |transform| {
+        let mut transformer = Transformer::new(transform);
+        values
+            .iter()
+            .enumerate()
+            .map(|(time, value)| transformer.push(time as Timestamp, *value))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        apply(Transform::MovingAverage { points: 2 }),
+        vec![None, Some(2.0), Some(4.0), Some(6.0), Some(5.5)]
+    );
+    assert_eq!(
+        apply(Transform::MovingSum { points: 3 }),
+        vec![None, None, Some(9.0), Some(15.0), Some(16.0)]
+    );
+    assert_eq!(
+        apply(Transform::CumulativeSum),
+        vec![Some(1.0), Some(4.0), Some(9.0), Some(16.0), Some(20.0)]
+    );
+    assert_eq!(
+        apply(Transform::Ewma { alpha: 0.5 }),
+        vec![Some(1.0), Some(2.0), Some(3.5), Some(5.25), Some(4.625)]
+    );
+}
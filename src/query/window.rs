@@ -0,0 +1,103 @@
+use anyhow::Result;
+
+use super::aggregate::{Accumulator, Aggregate};
+use crate::db::Timestamp;
+use crate::parser::select::TimeWindow;
+
+// Start of the window `time` falls into
+pub fn window_start(window: &TimeWindow, time: Timestamp) -> Timestamp {
+    // Widened so that windows around the ends of the time range can't overflow
+    let interval = i128::from(window.interval);
+    let offset = i128::from(window.offset);
+    let start = (i128::from(time) - offset).div_euclid(interval) * interval + offset;
+    start.clamp(i128::from(Timestamp::MIN), i128::from(Timestamp::MAX)) as Timestamp
+}
+
+/// Folds time ordered points into an accumulator per aggregate and window,
+/// returning the windows that had points. Without a window everything falls
+/// into a single one starting at `whole_range`, which is returned even if
+/// empty.
+pub fn fold_windows(
+    points: impl Iterator<Item = Result<(Timestamp, f64)>>,
+    aggregates: &[Aggregate],
+    window: Option<&TimeWindow>,
+    whole_range: Timestamp,
+) -> Result<Vec<(Timestamp, Vec<Accumulator>)>> {
+    let new = || aggregates.iter().map(|aggregate| Accumulator::new(*aggregate)).collect::<Vec<_>>();
+    let mut output = Vec::new();
+    let mut current: Option<(Timestamp, Vec<Accumulator>)> = None;
+    for point in points {
+        let (time, value) = point?;
+        let start = match window {
+            Some(window) => window_start(window, time),
+            None => whole_range,
+        };
+        match &mut current {
+            Some((current_start, accs)) if *current_start == start => {
+                accs.iter_mut().for_each(|acc| acc.push(time, value))
+            }
+            _ => {
+                if let Some((done_start, done)) = current.take() {
+                    output.push((done_start, done));
+                }
+                let mut accs = new();
+                accs.iter_mut().for_each(|acc| acc.push(time, value));
+                current = Some((start, accs));
+            }
+        }
+    }
+
+    match current {
+        Some((start, accs)) => output.push((start, accs)),
+        None if window.is_none() => output.push((whole_range, new())),
+        None => {}
+    }
+    Ok(output)
+}
+
+#[test]
+fn test_window_start() {
+    let five_minutes = TimeWindow {
+        interval: 300,
+        offset: 0,
+    };
+    assert_eq!(window_start(&five_minutes, 0), 0);
+    assert_eq!(window_start(&five_minutes, 299), 0);
+    assert_eq!(window_start(&five_minutes, 300), 300);
+    assert_eq!(window_start(&five_minutes, -1), -300);
+    assert_eq!(window_start(&five_minutes, Timestamp::MIN), Timestamp::MIN);
+
+    let shifted = TimeWindow {
+        interval: 3600,
+        offset: 900,
+    };
+    assert_eq!(window_start(&shifted, 900), 900);
+    assert_eq!(window_start(&shifted, 899), -2700);
+}
+
+#[test]
+fn test_fold_windows() {
+    let points = vec![(0, 1.0), (30, 3.0), (60, 5.0), (200, 7.0)];
+    let window = TimeWindow {
+        interval: 60,
+        offset: 0,
+    };
+    let folded: Vec<_> = fold_windows(points.into_iter().map(Ok), &[Aggregate::Avg, Aggregate::Max], Some(&window), 0)
+        .unwrap()
+        .into_iter()
+        .map(|(start, accs)| (start, accs.iter().map(Accumulator::finish).collect::<Vec<_>>()))
+        .collect();
+    assert_eq!(
+        folded,
+        vec![
+            (0, vec![Some(2.0), Some(3.0)]),
+            (60, vec![Some(5.0), Some(5.0)]),
+            (180, vec![Some(7.0), Some(7.0)])
+        ]
+    );
+
+    let empty = fold_windows(std::iter::empty(), &[Aggregate::Count], None, 42).unwrap();
+    assert_eq!(empty.len(), 1);
+    assert_eq!(empty[0].0, 42);
+    assert_eq!(empty[0].1[0].finish(), Some(0.0));
+}